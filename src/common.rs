@@ -1,7 +1,9 @@
 use anyhow::Result;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use walkdir::WalkDir;
+use crate::media::{self, MediaType};
 
 use serde::{Deserialize, Serialize};
 
@@ -19,62 +21,615 @@ impl Serialize for Zoned {
 
         state.serialize_field("local_datetime", &self.0.timestamp())?;
 
-        let tz_string = self.0.time_zone().iana_name().unwrap(); //TODO
-        state.serialize_field("time_zone", &tz_string)?;
+        state.serialize_field("time_zone", &time_zone_string(&self.0))?;
         //serializer.serialize_
         //state.serialize_field("timezone", &self.0.time_zone())?;
         state.end()
     }
 }
 
+static TIME_ZONE_PRINTER: jiff::fmt::temporal::DateTimePrinter =
+    jiff::fmt::temporal::DateTimePrinter::new();
+static TIME_ZONE_PARSER: jiff::fmt::temporal::DateTimeParser =
+    jiff::fmt::temporal::DateTimeParser::new();
+
+/// Time zone of `zoned` as text `time_zone_from_string` reads back, its IANA
+/// name, a fixed offset such as `+09:00` as EXIF gives, or a POSIX rule. A
+/// zone with none of them, a system zone outside the tz database, is kept as
+/// its offset at that time.
+pub fn time_zone_string(zoned: &jiff::Zoned) -> String {
+    let mut text = String::new();
+    if TIME_ZONE_PRINTER
+        .print_time_zone(zoned.time_zone(), &mut text)
+        .is_err()
+    {
+        text.clear();
+        let fixed = jiff::tz::TimeZone::fixed(zoned.offset());
+        TIME_ZONE_PRINTER
+            .print_time_zone(&fixed, &mut text)
+            .expect("fixed offsets print");
+    }
+    text
+}
+
+/// POSIX TZ rule of a zone which has neither an IANA name nor a fixed offset,
+/// such as one read from a `TZ` variable.
+pub fn posix_rule(time_zone: &jiff::tz::TimeZone) -> Option<String> {
+    if time_zone.iana_name().is_some() || time_zone.to_fixed_offset().is_ok() {
+        return None;
+    }
+    let mut text = String::new();
+    TIME_ZONE_PRINTER
+        .print_time_zone(time_zone, &mut text)
+        .ok()
+        .map(|()| text)
+}
+
+pub fn time_zone_from_string(text: &str) -> Result<jiff::tz::TimeZone> {
+    Ok(TIME_ZONE_PARSER.parse_time_zone(text)?)
+}
+
 #[derive(Serialize)]
 pub struct BasicPicture {
-    pub path: String,
+    /// Stays the same as long as the record exists, even when it is moved.
+    pub id: i64,
+    #[serde(serialize_with = "serialize_escaped_path")]
+    pub path: PathBuf,
+    pub media_type: MediaType,
     pub fs_create_time: Zoned,
+    pub fs_create_time_source: TimeSource,
+    pub fs_modify_time: Zoned,
     pub exif_create_time: Option<Zoned>,
 }
 
 pub struct PictureRecord {
-    pub path: String,
+    pub path: PathBuf,
+    pub media_type: MediaType,
+    pub size: u64,
+    pub inode: u64,
     pub fs_create_time: Zoned,
+    pub fs_create_time_source: TimeSource,
+    pub fs_modify_time: Zoned,
+    pub exif_create_time: Option<Zoned>,
+    /// See `metadata::content_hash`.
+    pub content_hash: [u8; 32],
+    /// See `metadata::perceptual_hash`.
+    pub perceptual_hash: Option<u64>,
+}
+
+/// Raw bytes of `path`, which stores keep so that any name the filesystem
+/// allows round trips, UTF-8 or not.
+pub fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// Inverse of `path_bytes`.
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    let path = <std::ffi::OsString as std::os::unix::ffi::OsStringExt>::from_vec(bytes);
+    #[cfg(not(unix))]
+    let path = String::from_utf8_lossy(&bytes).into_owned();
+
+    PathBuf::from(path)
+}
+
+/// Byte prefix shared by the stored paths below `root`, which match
+/// `path = root or path starts with prefix`.
+pub fn below(root: &Path) -> Vec<u8> {
+    let root = path_bytes(root);
+    let separator = std::path::MAIN_SEPARATOR_STR.as_bytes();
+    match root.ends_with(separator) {
+        true => root.to_vec(),
+        false => [root, separator].concat(),
+    }
+}
+
+/// `path` for display, bytes which are not UTF-8 escaped as `\xNN` and a
+/// backslash as `\\`, so the two can't be confused.
+pub fn escaped_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path_bytes(path).utf8_chunks() {
+        escaped.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{byte:02X}"));
+        }
+    }
+    escaped
+}
+
+pub(crate) fn serialize_escaped_path<S>(
+    path: &Path,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&escaped_path(path))
+}
+
+/// What a rescan compares to tell whether a file changed since it was stored.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FileIdentity {
+    pub size: u64,
+    pub inode: u64,
+    pub modify_time: jiff::Timestamp,
+}
+
+impl FileIdentity {
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Result<Self> {
+        #[cfg(unix)]
+        let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+        #[cfg(not(unix))]
+        let inode = 0;
+
+        Ok(FileIdentity {
+            size: metadata.len(),
+            inode,
+            modify_time: whole_micros(fs_modify_time(metadata)?.0.timestamp()),
+        })
+    }
+}
+
+/// `time` to the microsecond, the finest time the delta store keeps, so
+/// identities read back from either store compare equal to fresh ones.
+pub fn whole_micros(time: jiff::Timestamp) -> jiff::Timestamp {
+    jiff::Timestamp::from_microsecond(time.as_microsecond()).expect("within range")
 }
 
 impl PictureRecord {
-    pub fn new(entry: &walkdir::DirEntry) -> anyhow::Result<PictureRecord> {
-        let fs_create_time = jiff::Zoned::try_from(entry.metadata()?.created()?)?;
-        let path = entry
-            .path()
-            .to_str()
-            .ok_or_else(|| anyhow::anyhow!("invalid utf8"))?;
+    pub fn new(
+        path: &Path,
+        metadata: &std::fs::Metadata,
+        media_type: MediaType,
+    ) -> anyhow::Result<PictureRecord> {
+        let (fs_create_time, fs_create_time_source) = fs_create_time(metadata)?;
+        let (fs_modify_time, _) = fs_modify_time(metadata)?;
+        let identity = FileIdentity::from_metadata(metadata)?;
+        let exif_create_time = if !media_type.is_video() {
+            crate::metadata::exif_create_time(path)?
+        } else {
+            None
+        };
+        let content_hash = crate::metadata::content_hash(path)?;
+        let perceptual_hash = match media_type.is_decodable() {
+            true => crate::metadata::perceptual_hash(path),
+            false => None,
+        };
 
         Ok(PictureRecord {
             path: path.to_owned(),
+            media_type,
+            size: identity.size,
+            inode: identity.inode,
             fs_create_time: Zoned(fs_create_time),
+            fs_create_time_source,
+            fs_modify_time: Zoned(fs_modify_time),
+            exif_create_time: exif_create_time.map(Zoned),
+            content_hash,
+            perceptual_hash,
         })
     }
+
+    pub fn identity(&self) -> FileIdentity {
+        FileIdentity {
+            size: self.size,
+            inode: self.inode,
+            modify_time: whole_micros(self.fs_modify_time.0.timestamp()),
+        }
+    }
+}
+
+/// Which file time `fs_create_time` was taken from, the birth time when the
+/// filesystem keeps one. The others only bound it from above.
+#[derive(EnumString, AsRefStr, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeSource {
+    Birth,
+    Modify,
+    Change,
+}
+
+/// Birth time of the file, falling back to its modification then status
+/// change time on filesystems which don't record it, such as most NFS
+/// mounts.
+fn fs_create_time(metadata: &std::fs::Metadata) -> Result<(jiff::Zoned, TimeSource)> {
+    if let Ok(created) = metadata.created() {
+        return Ok((jiff::Zoned::try_from(created)?, TimeSource::Birth));
+    }
+    fs_modify_time(metadata)
+}
+
+/// Modification time of the file, falling back to its status change time
+/// on filesystems which don't record it.
+fn fs_modify_time(metadata: &std::fs::Metadata) -> Result<(jiff::Zoned, TimeSource)> {
+    if let Ok(modified) = metadata.modified() {
+        return Ok((jiff::Zoned::try_from(modified)?, TimeSource::Modify));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let changed = jiff::Timestamp::new(metadata.ctime(), metadata.ctime_nsec() as i32)?;
+        Ok((
+            changed.to_zoned(jiff::tz::TimeZone::system()),
+            TimeSource::Change,
+        ))
+    }
+    #[cfg(not(unix))]
+    Err(anyhow::anyhow!("no file time available"))
+}
+
+/// A change to the store, writers may queue these until flushed.
+pub enum FsOp {
+    Upsert(Box<PictureRecord>),
+    Remove(PathBuf),
+    Missing(PathBuf),
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        identity: FileIdentity,
+    },
+}
+
+impl FsOp {
+    /// Path of the file the change is about, where it lives afterwards.
+    pub fn path(&self) -> &Path {
+        match self {
+            FsOp::Upsert(record) => &record.path,
+            FsOp::Remove(path) | FsOp::Missing(path) => path,
+            FsOp::Move { to, .. } => to,
+        }
+    }
 }
 
 pub trait FsOpCallback: Send {
     fn on_op(&mut self, picture_record: PictureRecord) -> Result<()>;
     fn flush(&mut self) -> Result<()>;
+
+    /// Identity of every stored record under `root`, keyed by path, so a
+    /// rescan can skip files that did not change. Stores which can't tell
+    /// return nothing and every file is written again.
+    fn stored_identities(&mut self, _root: &Path) -> Result<HashMap<PathBuf, FileIdentity>> {
+        Ok(HashMap::new())
+    }
+
+    /// `PictureRecord::content_hash` of the record stored at `path`, which
+    /// confirms a move. Stores which can't tell return `None` and a moved
+    /// file is written again.
+    fn stored_content_hash(&mut self, _path: &Path) -> Result<Option<[u8; 32]>> {
+        Ok(None)
+    }
+
+    /// The file at `path` is gone, drop its record.
+    fn on_remove(&mut self, path: &Path) -> Result<()>;
+
+    /// The file at `path` is gone, keep its record but flag it as missing.
+    fn on_missing(&mut self, path: &Path) -> Result<()>;
+
+    /// The file at `from` now lives at `to`, only the path and identity of
+    /// the record change so everything else extracted from it is kept.
+    fn on_move(&mut self, from: &Path, to: &Path, identity: FileIdentity) -> Result<()>;
+
+    /// Keeps the report of a finished walk of `root`, when asked to with
+    /// `WalkOptions::persist_report`. Stores which can't drop it.
+    fn on_report(&mut self, _root: &Path, _report: &ScanReport) -> Result<()> {
+        Ok(())
+    }
+
+    /// Changes given up on since the last call, with the reason. A failed
+    /// `flush` keeps its changes queued for the next one, they are only
+    /// dropped when they can never be written or too many pile up.
+    fn take_dropped(&mut self) -> Vec<(PathBuf, anyhow::Error)> {
+        Vec::new()
+    }
+
+    /// Gives up on every queued change for `error`, `take_dropped` returns
+    /// them next. Stores which don't drop changes keep them queued.
+    fn drop_queued(&mut self, _error: &anyhow::Error) {}
 }
 
 use strum_macros::AsRefStr;
 
 use strum_macros::EnumString;
-#[derive(EnumString, AsRefStr)]
+#[derive(EnumString, AsRefStr, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum OrderBy {
     FsCreateTime,
     FsModifyTime,
     ExifCreateTime,
+    Path,
+}
+
+/// A key of a `Sort`, written `Key` ascending or `-Key` descending.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SortKey {
+    pub order_by: OrderBy,
+    pub descending: bool,
+}
+
+/// Order of `StoreReader::load`, records are compared by each key in turn,
+/// then by id in the direction of the last key. Records without a key come
+/// last whatever the direction. Written as keys joined by `,`, such as
+/// `-ExifCreateTime,Path`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Sort(pub Vec<SortKey>);
+
+impl Sort {
+    /// Whether ties are broken by descending id.
+    pub fn descending_id(&self) -> bool {
+        self.0.last().is_some_and(|key| key.descending)
+    }
+}
+
+impl From<OrderBy> for Sort {
+    fn from(order_by: OrderBy) -> Self {
+        Sort(vec![SortKey {
+            order_by,
+            descending: false,
+        }])
+    }
+}
+
+impl std::fmt::Display for Sort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, key) in self.0.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let sign = if key.descending { "-" } else { "" };
+            write!(f, "{separator}{sign}{}", key.order_by.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Sort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let keys = s
+            .split(',')
+            .map(|key| {
+                let (key, descending) = match key.strip_prefix('-') {
+                    Some(key) => (key, true),
+                    None => (key.strip_prefix('+').unwrap_or(key), false),
+                };
+                Ok(SortKey {
+                    order_by: key.parse()?,
+                    descending,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Sort(keys))
+    }
+}
+
+/// Restricts which records `StoreReader::load` returns.
+#[derive(Default, Clone)]
+pub struct Filter {
+    /// Only records of these media types, any type when empty.
+    pub media_types: Vec<MediaType>,
+    /// Only records with these `BasicPicture::id`.
+    pub ids: Option<Vec<i64>>,
+    /// Only records ordered after this one, the next page of a listing.
+    pub after: Option<Cursor>,
+    /// Only records with a time in this range.
+    pub time_range: Option<TimeRange>,
+    /// Only records at or below this path.
+    pub path_prefix: Option<PathBuf>,
+    /// Only records with a path matching this glob, as sqlite's `glob`:
+    /// `*`, `?` and classes like `[a-z]` or `[^0-9]`, all of which also
+    /// match `/`.
+    pub path_glob: Option<String>,
+    /// Only records of at least this many bytes.
+    pub min_size: Option<u64>,
+    /// Only records of at most this many bytes.
+    pub max_size: Option<u64>,
+    /// Records as they were then rather than now, only for stores keeping
+    /// their history.
+    pub as_of: Option<AsOf>,
+}
+
+/// A past version of a store keeping its history.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AsOf {
+    Version(i64),
+    /// The latest version committed at or before.
+    Time(jiff::Timestamp),
+}
+
+/// A version number or an RFC 3339 time.
+impl std::str::FromStr for AsOf {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.parse() {
+            Ok(version) => Ok(AsOf::Version(version)),
+            Err(_) => Ok(AsOf::Time(s.parse()?)),
+        }
+    }
+}
+
+/// The `key` time of a record from `since` included to `until` excluded,
+/// either end may be open. Records without that time are never in range,
+/// and `OrderBy::Path` is not a time.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TimeRange {
+    pub key: OrderBy,
+    pub since: Option<jiff::Timestamp>,
+    pub until: Option<jiff::Timestamp>,
+}
+
+/// Value of a sort key for one record.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum SortValue {
+    /// `None` for records without that time.
+    Time(Option<jiff::Timestamp>),
+    Path(PathBuf),
+}
+
+/// Position of a record in a `load` ordering, its sort key values with the
+/// id as tiebreaker, so pages can follow each other without offsets.
+/// Written as an opaque token by `Display` and read back by `FromStr`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Cursor {
+    pub sort: Sort,
+    /// One for each key of `sort`.
+    pub values: Vec<SortValue>,
+    pub id: i64,
+}
+
+impl Cursor {
+    /// The cursor of `picture`, where the page after it starts.
+    pub fn after(sort: Sort, picture: &BasicPicture) -> Self {
+        let values = sort
+            .0
+            .iter()
+            .map(|key| {
+                let time = match key.order_by {
+                    OrderBy::FsCreateTime => Some(&picture.fs_create_time),
+                    OrderBy::FsModifyTime => Some(&picture.fs_modify_time),
+                    OrderBy::ExifCreateTime => picture.exif_create_time.as_ref(),
+                    OrderBy::Path => return SortValue::Path(picture.path.clone()),
+                };
+                SortValue::Time(time.map(|zoned| zoned.0.timestamp()))
+            })
+            .collect();
+        Cursor {
+            sort,
+            values,
+            id: picture.id,
+        }
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.sort)?;
+        for value in &self.values {
+            match value {
+                SortValue::Time(Some(time)) => write!(f, ".{}", time.as_nanosecond())?,
+                SortValue::Time(None) => write!(f, ".")?,
+                SortValue::Path(path) => {
+                    write!(f, ".")?;
+                    for byte in path_bytes(path) {
+                        write!(f, "{byte:02x}")?;
+                    }
+                }
+            }
+        }
+        write!(f, ".{}", self.id)
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split('.').collect();
+        let [sort, values @ .., id] = parts.as_slice() else {
+            anyhow::bail!("malformed cursor {s}");
+        };
+        let sort: Sort = sort.parse()?;
+        if values.len() != sort.0.len() {
+            anyhow::bail!("malformed cursor {s}");
+        }
+        let values = sort
+            .0
+            .iter()
+            .zip(values)
+            .map(|(key, value)| {
+                Ok(match (key.order_by, *value) {
+                    (OrderBy::Path, hex) => {
+                        let bytes = (0..hex.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("-"), 16))
+                            .collect::<std::result::Result<Vec<_>, _>>()?;
+                        SortValue::Path(path_from_bytes(bytes))
+                    }
+                    (_, "") => SortValue::Time(None),
+                    (_, time) => {
+                        SortValue::Time(Some(jiff::Timestamp::from_nanosecond(time.parse()?)?))
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Cursor {
+            sort,
+            values,
+            id: id.parse()?,
+        })
+    }
+}
+
+/// Files with the same content, see `StoreReader::duplicates`.
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct DuplicateGroup {
+    /// Hex of `PictureRecord::content_hash`.
+    pub content_hash: String,
+    pub size: u64,
+    #[serde(serialize_with = "serialize_escaped_paths")]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(EnumString, AsRefStr, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Granularity {
+    Year,
+    Month,
+    Day,
+}
+
+impl Granularity {
+    /// First day of the period holding `date`.
+    pub fn start(&self, date: jiff::civil::Date) -> jiff::civil::Date {
+        match self {
+            Granularity::Year => date.first_of_year(),
+            Granularity::Month => date.first_of_month(),
+            Granularity::Day => date,
+        }
+    }
+}
+
+/// Records in the period starting on `start`, see `StoreReader::timeline`.
+#[derive(Serialize, PartialEq, Eq, Debug)]
+pub struct TimelineBucket {
+    pub start: jiff::civil::Date,
+    pub count: u64,
+}
+
+fn serialize_escaped_paths<S>(
+    paths: &[PathBuf],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(paths.iter().map(|path| escaped_path(path)))
 }
 
 pub trait StoreReader: Send {
     fn load(
         &mut self,
-        order_by: OrderBy,
+        sort: Sort,
+        filter: Filter,
         limit: usize,
     ) -> Result<impl Iterator<Item = BasicPicture>>;
+
+    /// Groups of present files with identical content, largest files first,
+    /// at most `limit` groups unless 0. Stores which don't keep content
+    /// hashes find none.
+    fn duplicates(&mut self, _limit: usize) -> Result<Vec<DuplicateGroup>> {
+        Ok(vec![])
+    }
+
+    /// Number of records matching `filter` in each `granularity` period of
+    /// their `key` time, as seen in the time zone of the record. Records
+    /// without that time are left out, and so are empty periods. Stores
+    /// which can't count find none.
+    fn timeline(
+        &mut self,
+        _key: OrderBy,
+        _granularity: Granularity,
+        _filter: Filter,
+    ) -> Result<Vec<TimelineBucket>> {
+        Ok(vec![])
+    }
 }
 
 pub trait Store {
@@ -82,19 +637,360 @@ pub trait Store {
     fn writer(&self) -> impl FsOpCallback;
 }
 
-pub fn walk_files(root: &Path, callback: &mut dyn FsOpCallback) -> Result<()> {
-    let walker = WalkDir::new(root);
+/// Outcome of `walk_files`, compared with what the store held before.
+#[derive(Default, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ScanReport {
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub moved: usize,
+    pub missing: usize,
+    /// Files and directories that could not be indexed. The walk goes on
+    /// past them, and records below an unreadable directory are kept as is.
+    pub errors: Vec<ScanError>,
+}
 
-    for entry in walker.into_iter() {
-        match entry {
-            Ok(entry) => {
-                if let Ok(record) = PictureRecord::new(&entry) {
-                    let _ = callback.on_op(record);
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct ScanError {
+    /// Escaped as by `escaped_path`.
+    pub path: String,
+    pub kind: ScanErrorKind,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ScanErrorKind {
+    PermissionDenied,
+    /// The filesystem or the file format lacks something a record needs,
+    /// such as a creation time.
+    UnsupportedMetadata,
+    Io,
+    /// The store refused the change.
+    Store,
+}
+
+impl ScanError {
+    fn new(path: &Path, error: &anyhow::Error) -> Self {
+        let kind = match error.downcast_ref::<std::io::Error>() {
+            Some(e) => ScanErrorKind::from(e),
+            None => ScanErrorKind::UnsupportedMetadata,
+        };
+        ScanError {
+            path: escaped_path(path),
+            kind,
+            message: error.to_string(),
+        }
+    }
+
+    fn store(path: &Path, error: &anyhow::Error) -> Self {
+        ScanError {
+            path: escaped_path(path),
+            kind: ScanErrorKind::Store,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<&std::io::Error> for ScanErrorKind {
+    fn from(error: &std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::PermissionDenied => ScanErrorKind::PermissionDenied,
+            std::io::ErrorKind::Unsupported => ScanErrorKind::UnsupportedMetadata,
+            _ => ScanErrorKind::Io,
+        }
+    }
+}
+
+/// What a rescan does with stored records whose file is gone.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MissingPolicy {
+    /// Keep the record, flagged so readers skip it.
+    #[default]
+    Mark,
+    /// Delete the record.
+    Remove,
+}
+
+impl MissingPolicy {
+    pub fn apply(&self, callback: &mut dyn FsOpCallback, path: &Path) -> Result<()> {
+        match self {
+            MissingPolicy::Mark => callback.on_missing(path),
+            MissingPolicy::Remove => callback.on_remove(path),
+        }
+    }
+}
+
+#[derive(Default, Clone, Debug)]
+pub struct WalkOptions {
+    pub missing: MissingPolicy,
+    /// Threads reading directories, and threads stating files and extracting
+    /// their metadata. 0 for one per cpu.
+    pub workers: usize,
+    /// Hand the report of the walk to the store, see `FsOpCallback::on_report`.
+    pub persist_report: bool,
+}
+
+/// Files waiting for a worker, per worker.
+const QUEUE_PER_WORKER: usize = 64;
+
+/// What a worker found out about one walked file.
+enum Scanned {
+    Unchanged(PathBuf),
+    Changed {
+        path: PathBuf,
+        identity: FileIdentity,
+        /// `None` when it's not a media file.
+        record: Option<Box<PictureRecord>>,
+    },
+    /// The file exists but could not be indexed.
+    Failed(PathBuf, ScanError),
+    /// Nothing below `path` was walked.
+    Unwalked(PathBuf, ScanError),
+}
+
+pub fn walk_files(root: &Path, callback: &mut dyn FsOpCallback) -> Result<ScanReport> {
+    walk_files_with(root, callback, &WalkOptions::default())
+}
+
+/// Walks `root` and reconciles the store with it: new and changed files are
+/// written, stored records whose file is gone are handled as
+/// `options.missing` says, unless their directory could not be read.
+///
+/// A new file with the same size and modification time as a vanished one,
+/// which `mv` and most sync tools preserve, and the same content hash is
+/// taken as a move.
+///
+/// Directories are read and files are stated and parsed by
+/// `options.workers` threads each, all results go through `callback` on
+/// the calling thread.
+pub fn walk_files_with(
+    root: &Path,
+    callback: &mut dyn FsOpCallback,
+    options: &WalkOptions,
+) -> Result<ScanReport> {
+    walk_files_until(root, callback, options, &AtomicBool::new(false))
+}
+
+/// `walk_files_with` which gives up once `stop` is set. What was found so
+/// far is written, records of files it didn't get to are left alone and
+/// no report is kept.
+pub fn walk_files_until(
+    root: &Path,
+    callback: &mut dyn FsOpCallback,
+    options: &WalkOptions,
+    stop: &AtomicBool,
+) -> Result<ScanReport> {
+    let stored = callback.stored_identities(root)?;
+    let mut unseen: HashSet<&Path> = stored.keys().map(|path| path.as_path()).collect();
+    let mut move_candidates: HashMap<(u64, jiff::Timestamp), Vec<&Path>> = HashMap::new();
+    for (path, identity) in stored.iter() {
+        move_candidates
+            .entry((identity.size, identity.modify_time))
+            .or_default()
+            .push(path);
+    }
+
+    let workers = match options.workers {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let (paths_tx, paths_rx) = crossbeam_channel::bounded::<PathBuf>(workers * QUEUE_PER_WORKER);
+    let (scanned_tx, scanned_rx) = crossbeam_channel::bounded(workers * QUEUE_PER_WORKER);
+
+    let mut report = ScanReport::default();
+    let mut unwalked: Vec<PathBuf> = vec![];
+
+    std::thread::scope(|s| {
+        let walker_scanned_tx = scanned_tx.clone();
+        s.spawn(move || {
+            let walker = jwalk::WalkDir::new(root)
+                .skip_hidden(false)
+                .parallelism(jwalk::Parallelism::RayonNewPool(workers))
+                .process_read_dir(|_, _, _, children| {
+                    children.retain(|child| match child {
+                        Ok(child) => {
+                            !media::is_ignored(child.file_name(), child.file_type().is_dir())
+                        }
+                        Err(_) => true,
+                    })
+                });
+            for entry in walker {
+                if stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                let scanned = match entry {
+                    Ok(entry) if entry.file_type().is_file() => {
+                        if paths_tx.send(entry.path()).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    Ok(entry) => match entry.read_children_error {
+                        Some(ref e) => unwalked_dir(e, &entry.path()),
+                        None => continue,
+                    },
+                    Err(ref e) => unwalked_dir(e, root),
+                };
+                if walker_scanned_tx.send(scanned).is_err() {
+                    break;
+                }
+            }
+        });
+
+        for _ in 0..workers {
+            let (paths_rx, scanned_tx) = (paths_rx.clone(), scanned_tx.clone());
+            let stored = &stored;
+            s.spawn(move || {
+                for path in paths_rx {
+                    if scanned_tx.send(scan_file(path, stored)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop((paths_rx, scanned_tx));
+
+        for scanned in scanned_rx {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            let (path, identity, record) = match scanned {
+                Scanned::Unchanged(path) => {
+                    unseen.remove(path.as_path());
+                    report.unchanged += 1;
+                    continue;
+                }
+                Scanned::Changed {
+                    path,
+                    identity,
+                    record,
+                } => (path, identity, record),
+                Scanned::Failed(path, error) => {
+                    unseen.remove(path.as_path());
+                    report.errors.push(error);
+                    continue;
+                }
+                Scanned::Unwalked(path, error) => {
+                    unwalked.push(path);
+                    report.errors.push(error);
+                    continue;
+                }
+            };
+
+            let was_stored = unseen.remove(path.as_path()) || stored.contains_key(&path);
+
+            if !was_stored && let Some(record) = &record {
+                let candidates = move_candidates
+                    .get(&(identity.size, identity.modify_time))
+                    .map_or(&[][..], |candidates| candidates.as_slice());
+                let moved_from = candidates.iter().find(|from| {
+                    unseen.contains(*from)
+                        && std::fs::symlink_metadata(from).is_err()
+                        && callback
+                            .stored_content_hash(from)
+                            .is_ok_and(|hash| hash == Some(record.content_hash))
+                });
+                if let Some(from) = moved_from {
+                    unseen.remove(from);
+                    match callback.on_move(from, &path, identity) {
+                        Ok(()) => report.moved += 1,
+                        Err(e) => report.errors.push(ScanError::store(&path, &e)),
+                    }
+                    continue;
                 }
             }
-            Err(_) => break,
+
+            let Some(record) = record else {
+                continue;
+            };
+            match callback.on_op(*record) {
+                Ok(()) if was_stored => report.updated += 1,
+                Ok(()) => report.inserted += 1,
+                Err(e) => report.errors.push(ScanError::store(&path, &e)),
+            }
         }
+    });
+
+    if stop.load(Ordering::Relaxed) {
+        finish_writes(callback, &mut report);
+        return Ok(report);
     }
 
-    callback.flush()
+    // files below a directory that could not be read may well still exist
+    for path in unseen {
+        if unwalked.iter().any(|dir| path.starts_with(dir)) {
+            continue;
+        }
+        match options.missing.apply(callback, path) {
+            Ok(()) => report.missing += 1,
+            Err(e) => report.errors.push(ScanError::store(path, &e)),
+        }
+    }
+
+    finish_writes(callback, &mut report);
+    if options.persist_report {
+        callback.on_report(root, &report)?;
+    }
+    Ok(report)
+}
+
+/// Flushes the changes of a walk, a final flush which fails gives them up.
+/// Those the store gave up on are errors of `report`.
+fn finish_writes(callback: &mut dyn FsOpCallback, report: &mut ScanReport) {
+    if let Err(error) = callback.flush() {
+        callback.drop_queued(&error);
+    }
+    for (path, error) in callback.take_dropped() {
+        report.errors.push(ScanError::store(&path, &error));
+    }
+}
+
+/// A directory the walker could not read, or `fallback` when the error
+/// does not tell which one.
+fn unwalked_dir(error: &jwalk::Error, fallback: &Path) -> Scanned {
+    let path = error.path().unwrap_or(fallback).to_owned();
+    let error = ScanError {
+        path: escaped_path(&path),
+        kind: error
+            .io_error()
+            .map_or(ScanErrorKind::Io, ScanErrorKind::from),
+        message: error.to_string(),
+    };
+    Scanned::Unwalked(path, error)
+}
+
+/// Stats `path`, and when it differs from what is stored extracts its
+/// record.
+fn scan_file(path: PathBuf, stored: &HashMap<PathBuf, FileIdentity>) -> Scanned {
+    let failed = |path: PathBuf, error: anyhow::Error| {
+        let error = ScanError::new(&path, &error);
+        Scanned::Failed(path, error)
+    };
+
+    let metadata = match std::fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) => return failed(path, e.into()),
+    };
+    let identity = match FileIdentity::from_metadata(&metadata) {
+        Ok(identity) => identity,
+        Err(e) => return failed(path, e),
+    };
+
+    if stored.get(&path) == Some(&identity) {
+        return Scanned::Unchanged(path);
+    }
+
+    let record = match media::detect(&path) {
+        Ok(Some(media_type)) => match PictureRecord::new(&path, &metadata, media_type) {
+            Ok(record) => Some(Box::new(record)),
+            Err(e) => return failed(path, e),
+        },
+        Ok(None) => None,
+        Err(e) => return failed(path, e),
+    };
+    Scanned::Changed {
+        path,
+        identity,
+        record,
+    }
 }
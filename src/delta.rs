@@ -1,187 +1,1422 @@
-#[cfg(feature = "delta")]
-mod a {
-    use deltalake::{
-        kernel::{
-            Action, Add, Invariant, LogicalFile, Remove, StructType, Transaction,
-            scalars::ScalarExt,
-        },
-        operations::optimize::OptimizeType,
-    };
-    use polars_lazy::frame::{LazyFrame, ScanArgsParquet};
-    use std::sync::Arc;
+#![cfg(feature = "delta")]
 
-    use anyhow::{Result, anyhow};
-    use std::{
-        path::{Path, PathBuf},
-        vec,
-    };
+use deltalake::PartitionFilter;
+use deltalake::datafusion::prelude::SessionContext;
+use deltalake::delta_datafusion::DeltaCdfTableProvider;
+use deltalake::kernel::{Action, Metadata, StructType};
+use deltalake::operations::optimize::OptimizeType;
+use deltalake::operations::transaction::CommitBuilder;
+use polars_lazy::frame::{LazyFrame, ScanArgsParquet};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+use std::{
+    path::{Path, PathBuf},
+    vec,
+};
+
+use arrow::array::{Array, AsArray};
+use arrow::array::{
+    ArrayRef, BinaryArray, BooleanArray, Int32Array, Int64Array, StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow::datatypes::Int64Type;
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+
+use crate::common::{
+    AsOf, BasicPicture, Cursor, FileIdentity, Filter, FsOp, FsOpCallback, Granularity, OrderBy,
+    PictureRecord, Sort, SortValue, Store, StoreReader, TimeSource, TimelineBucket, Zoned, below,
+    path_bytes, path_from_bytes, time_zone_from_string, time_zone_string,
+};
+use crate::media::MediaType;
+use deltalake::DeltaOps;
+use deltalake::protocol::{DeltaOperation, SaveMode};
+use deltalake::writer::{DeltaWriter as _, RecordBatchWriter};
+use deltalake::{DeltaTable, arrow::array::RecordBatch};
+
+/// Columns of the sqlite `records` table. Times are kept in microseconds,
+/// the finest delta has, next to the time zone they were taken in. Sizes
+/// and inodes are signed as delta has no unsigned types.
+fn schema() -> Schema {
+    let timestamp = DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()));
+    Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("path", DataType::Binary, false),
+        Field::new("media_type", DataType::Utf8, false),
+        Field::new("size", DataType::Int64, false),
+        Field::new("inode", DataType::Int64, false),
+        Field::new("fs_create_time", timestamp.clone(), false),
+        Field::new("fs_create_time_timezone", DataType::Utf8, false),
+        Field::new("fs_create_time_source", DataType::Utf8, false),
+        Field::new("fs_modify_time", timestamp.clone(), false),
+        Field::new("fs_modify_time_timezone", DataType::Utf8, false),
+        Field::new("exif_create_time", timestamp, true),
+        Field::new("exif_create_time_timezone", DataType::Utf8, true),
+        Field::new("content_hash", DataType::Binary, true),
+        Field::new("perceptual_hash", DataType::Int64, true),
+        Field::new("missing", DataType::Boolean, false),
+        Field::new("capture_year", DataType::Int32, false),
+        Field::new("capture_month", DataType::Int32, false),
+    ])
+}
+
+/// Files are laid out by the UTC year and month of the capture time, the
+/// exif time or else `fs_create_time`, so a range of exif times only reads
+/// the months it spans.
+const PARTITION_COLUMNS: [&str; 2] = ["capture_year", "capture_month"];
+
+/// Year and month of the partition of `record`.
+fn capture_month(record: &PictureRecord) -> (i32, i32) {
+    let capture = record
+        .exif_create_time
+        .as_ref()
+        .unwrap_or(&record.fs_create_time);
+    let date = capture
+        .0
+        .timestamp()
+        .to_zoned(jiff::tz::TimeZone::UTC)
+        .date();
+    (date.year().into(), date.month().into())
+}
+
+/// Table property which makes every commit record its row changes, the
+/// feed `changes` reads.
+const CHANGE_DATA_FEED: &str = "delta.enableChangeDataFeed";
+
+/// Rewrites a table from before the capture month partitions into them,
+/// keeping every row and id. Delta writes can't change the partitions of
+/// a table, so the files of every row are written aside and one commit
+/// swaps them in together with the partitioned metadata, readers see
+/// either layout whole.
+fn partition(mut table: DeltaTable, schema: &Schema) -> Result<DeltaTable> {
+    let runtime = deltalake::storage::IORuntime::default().get_handle();
+    let ctx = SessionContext::new();
+    ctx.register_table("unpartitioned", Arc::new(table.clone()))?;
+    let capture = "coalesce(exif_create_time, fs_create_time)";
+    let query = format!(
+        "select *, cast(date_part('year', {capture}) as int) as capture_year, \
+         cast(date_part('month', {capture}) as int) as capture_month from unpartitioned"
+    );
+    let batches = runtime.block_on(async { ctx.sql(&query).await?.collect().await })?;
+    let batches = batches
+        .iter()
+        .map(|batch| {
+            let columns = schema
+                .fields()
+                .iter()
+                .zip(batch.columns())
+                .map(|(field, column)| arrow::compute::cast(column, field.data_type()))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(RecordBatch::try_new(Arc::new(schema.clone()), columns)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let partition_columns = PARTITION_COLUMNS.map(String::from).to_vec();
+
+    runtime.block_on(async {
+        let mut writer = RecordBatchWriter::try_new(
+            table.table_uri(),
+            Arc::new(schema.clone()),
+            Some(partition_columns.clone()),
+            None,
+        )?;
+        for batch in batches {
+            writer.write(batch).await?;
+        }
+
+        let snapshot = table.snapshot()?;
+        let mut actions: Vec<Action> = snapshot
+            .log_data()
+            .into_iter()
+            .map(|file| file.remove_action(true).into())
+            .collect();
+        let metadata = snapshot.metadata();
+        actions.push(Action::Metadata(
+            Metadata::try_new(
+                schema.try_into()?,
+                PARTITION_COLUMNS,
+                metadata.configuration.clone(),
+            )?
+            .with_table_id(metadata.id.clone()),
+        ));
+        actions.extend(writer.flush().await?.into_iter().map(Action::Add));
+
+        CommitBuilder::default()
+            .with_actions(actions)
+            .build(
+                Some(snapshot),
+                table.log_store(),
+                DeltaOperation::Write {
+                    mode: SaveMode::Overwrite,
+                    partition_by: Some(partition_columns),
+                    predicate: None,
+                },
+            )
+            .await?;
+        table.update().await?;
+        Ok(table)
+    })
+}
+
+pub struct SaveToDelta {
+    table: DeltaTable,
+
+    schema: Schema,
+}
+
+impl SaveToDelta {
+    pub fn new(uri: &str) -> Result<Self> {
+        let schema = schema();
+
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let jh = runtime.spawn(deltalake::DeltaTableBuilder::from_uri(uri).load());
+        let table: DeltaTable = match runtime.block_on(jh)? {
+            Ok(table) => table,
+            Err(_) => {
+                let table = deltalake::DeltaTableBuilder::from_uri(uri).build()?;
+                let ops = DeltaOps(table);
+
+                let sch: StructType = (&schema).try_into()?;
+
+                let jh = runtime.spawn(
+                    ops.create()
+                        .with_columns(sch.fields().cloned())
+                        .with_partition_columns(PARTITION_COLUMNS)
+                        .with_configuration([(CHANGE_DATA_FEED, Some("true"))])
+                        .into_future(),
+                );
 
-    use arrow::array::{Int32Array, StringArray};
-    use arrow_schema::{DataType, Field, Schema};
+                runtime.block_on(jh)??
+            }
+        };
 
-    use crate::common::{FsOpCallback, OrderBy, Picture, Store, StoreReader};
-    use deltalake::DeltaOps;
-    use deltalake::{DeltaTable, arrow::array::RecordBatch};
+        // tables written before every column was kept can't be upgraded in
+        // place, their rows lack the ids and times
+        let columns: Vec<String> = table
+            .get_schema()?
+            .fields()
+            .map(|f| f.name().to_owned())
+            .collect();
+        let expected: Vec<&str> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        let table = if columns == expected[..expected.len() - PARTITION_COLUMNS.len()] {
+            partition(table, &schema)?
+        } else if columns == expected {
+            table
+        } else {
+            bail!("delta table {uri} has columns {columns:?}, rescan into a new table");
+        };
 
-    #[cfg(feature = "delta")]
-    pub struct SaveToDelta {
-        table: DeltaTable,
+        // tables created before the feed only have it from here on
+        let table = match table.snapshot()?.table_config().enable_change_data_feed() {
+            true => table,
+            false => runtime.block_on(
+                DeltaOps(table)
+                    .set_tbl_properties()
+                    .with_properties(HashMap::from([(
+                        CHANGE_DATA_FEED.to_owned(),
+                        "true".to_owned(),
+                    )]))
+                    .into_future(),
+            )?,
+        };
 
-        schema: Schema,
+        Ok(SaveToDelta { schema, table })
     }
-    impl SaveToDelta {
-        pub fn new(uri: &str) -> Result<Self> {
-            let schema = Schema::new(vec![Field::new("path", DataType::Utf8, false)]);
 
-            let runtime = deltalake::storage::IORuntime::default().get_handle();
-            let jh = runtime.spawn(deltalake::DeltaTableBuilder::from_uri(uri).load());
-            let table: DeltaTable = match runtime.block_on(jh)? {
-                Ok(table) => table,
-                Err(_) => {
-                    let table = deltalake::DeltaTableBuilder::from_uri(uri).build()?;
-                    let ops = DeltaOps(table);
+    /// Rewrites the small files every flush adds into few large ones.
+    /// Returns the number of files added and removed.
+    pub fn optimize(&mut self, options: &MaintenanceOptions) -> Result<(u64, u64)> {
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime.block_on(self.table.update())?;
 
-                    let sch: StructType = (&schema).try_into()?;
+        let optimize_type = match options.z_order {
+            true => OptimizeType::ZOrder(vec!["exif_create_time".to_owned()]),
+            false => OptimizeType::Compact,
+        };
+        let (table, metrics) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .optimize()
+                .with_type(optimize_type)
+                .with_target_size(options.target_size)
+                .into_future(),
+        )?;
+        self.table = table;
+        Ok((metrics.num_files_added, metrics.num_files_removed))
+    }
 
-                    let jh = runtime.spawn(
-                        ops.create()
-                            .with_columns(sch.fields().cloned())
-                            .into_future(),
-                    );
+    /// Deletes the files no version younger than `retention_hours` refers
+    /// to, returning their uris. Readers still on an older version and time
+    /// travel to one fail afterwards.
+    pub fn vacuum(&mut self, retention_hours: u64) -> Result<Vec<String>> {
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime.block_on(self.table.update())?;
 
-                    runtime.block_on(jh)??
-                }
-            };
-            //table.update().await?;
+        let retention = chrono::Duration::hours(retention_hours.try_into()?);
+        let (table, metrics) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .vacuum()
+                .with_retention_period(retention)
+                // the configured retention is the policy, even when shorter
+                // than the week delta insists on by default
+                .with_enforce_retention_duration(false)
+                .into_future(),
+        )?;
+        self.table = table;
+        Ok(metrics.files_deleted)
+    }
+
+    /// Makes the table what it was at `as_of` again, as a new version so
+    /// the versions in between can still be read.
+    pub fn restore(&mut self, as_of: AsOf) -> Result<()> {
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime.block_on(self.table.update())?;
+
+        let restore = DeltaOps(self.table.clone()).restore();
+        let restore = match as_of {
+            AsOf::Version(version) => restore.with_version_to_restore(version),
+            AsOf::Time(time) => restore.with_datetime_to_restore(chrono_time(time)?),
+        };
+        let (table, _) = runtime.block_on(restore.into_future())?;
+        self.table = table;
+        Ok(())
+    }
+
+    /// `optimize` then `vacuum` the files it replaced once they are old
+    /// enough.
+    pub fn maintain(&mut self, options: &MaintenanceOptions) -> Result<MaintenanceReport> {
+        let (files_added, files_removed) = self.optimize(options)?;
+        let files_vacuumed = self.vacuum(options.retention_hours)?.len();
+        Ok(MaintenanceReport {
+            files_added,
+            files_removed,
+            files_vacuumed,
+        })
+    }
+}
+
+/// A version of the table and the commit which made it.
+#[derive(Clone, Debug, Serialize)]
+pub struct TableVersion {
+    pub version: i64,
+    pub timestamp: Option<jiff::Timestamp>,
+    /// Such as `WRITE`, `MERGE`, `DELETE` or `OPTIMIZE`.
+    pub operation: Option<String>,
+    pub operation_parameters: HashMap<String, serde_json::Value>,
+}
 
-            Ok(SaveToDelta { schema, table })
+/// The latest `limit` versions of the table at `uri`, newest first, all
+/// of them for 0, each with the commit read from its own log entry.
+/// Versions vacuumed away are still listed but can no longer be read.
+pub fn versions(uri: &str, limit: usize) -> Result<Vec<TableVersion>> {
+    let runtime = deltalake::storage::IORuntime::default().get_handle();
+    let table = runtime.block_on(deltalake::DeltaTableBuilder::from_uri(uri).load())?;
+    let log_store = table.log_store();
+
+    let mut versions = vec![];
+    for version in (0..=table.version()).rev() {
+        if limit != 0 && versions.len() == limit {
+            break;
         }
+        // commits older than the log keeps are gone, and so are the rest
+        let Some(bytes) = runtime.block_on(log_store.read_commit_entry(version))? else {
+            break;
+        };
+        let commit = runtime
+            .block_on(deltalake::logstore::get_actions(version, bytes))?
+            .into_iter()
+            .find_map(|action| match action {
+                deltalake::kernel::Action::CommitInfo(commit) => Some(commit),
+                _ => None,
+            })
+            .unwrap_or_default();
+        versions.push(TableVersion {
+            version,
+            timestamp: commit
+                .timestamp
+                .and_then(|ms| jiff::Timestamp::from_millisecond(ms).ok()),
+            operation: commit.operation,
+            operation_parameters: commit.operation_parameters.unwrap_or_default(),
+        });
     }
+    Ok(versions)
+}
 
-    impl Store for SaveToDelta {
-        fn reader(&self) -> impl StoreReader {
-            DeltaReader {
-                table: self.table.clone(),
-            }
+/// How `SaveToDelta::maintain` compacts and vacuums the table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceOptions {
+    /// Cluster rewritten files by `exif_create_time`, the time partitions
+    /// are laid out by, so that exif time ranges skip most files within a
+    /// month too, only bin-pack them otherwise.
+    pub z_order: bool,
+    /// Size in bytes rewritten files aim for.
+    pub target_size: i64,
+    /// How long replaced files are kept for readers and time travel.
+    pub retention_hours: u64,
+}
+
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        MaintenanceOptions {
+            z_order: true,
+            target_size: 100 << 20,
+            retention_hours: 7 * 24,
         }
+    }
+}
 
-        fn writer(&self) -> impl FsOpCallback {
-            DeltaWriter {
-                table: self.table.clone(),
-                queue: vec![],
-                schema: self.schema.clone(),
-            }
+#[derive(Default, Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct MaintenanceReport {
+    pub files_added: u64,
+    pub files_removed: u64,
+    pub files_vacuumed: usize,
+}
+
+impl Store for SaveToDelta {
+    fn reader(&self) -> impl StoreReader {
+        DeltaReader {
+            table: self.table.clone(),
+        }
+    }
+
+    fn writer(&self) -> impl FsOpCallback {
+        DeltaWriter {
+            table: self.table.clone(),
+            queue: vec![],
+            schema: self.schema.clone(),
         }
     }
+}
+
+pub struct DeltaReader {
+    table: DeltaTable,
+}
+pub struct DeltaWriter {
+    queue: vec::Vec<FsOp>,
+    schema: Schema,
+    table: DeltaTable,
+}
+use polars::prelude::*;
+use polars_lazy::prelude::{Expr, GetOutput, col, lit};
+
+impl DeltaReader {
+    pub fn new(uri: &str) -> Result<Self> {
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let table = runtime.block_on(deltalake::DeltaTableBuilder::from_uri(uri).load())?;
+        Ok(DeltaReader { table })
+    }
+}
+
+/// `time` as delta takes it.
+fn chrono_time(time: jiff::Timestamp) -> Result<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::from_timestamp(time.as_second(), time.subsec_nanosecond() as u32)
+        .ok_or_else(|| anyhow!("{time} out of range"))
+}
+
+/// Partitions which may hold records of `filter`, bounds on the capture
+/// month when it has a range of exif times.
+fn partition_filters(filter: &Filter) -> Result<Vec<PartitionFilter>> {
+    let Some(range) = &filter.time_range else {
+        return Ok(vec![]);
+    };
+    if range.key != OrderBy::ExifCreateTime {
+        return Ok(vec![]);
+    }
+    let month = |time: jiff::Timestamp| {
+        let date = time.to_zoned(jiff::tz::TimeZone::UTC).date();
+        (date.year(), date.month())
+    };
+    let first = range.since.map(month);
+    // until is excluded
+    let last = match range.until {
+        Some(until) => Some(month(
+            until.checked_sub(jiff::SignedDuration::from_nanos(1))?,
+        )),
+        None => None,
+    };
+
+    let mut filters = vec![];
+    if let Some((year, _)) = first {
+        filters.push(PartitionFilter::try_from((
+            "capture_year",
+            ">=",
+            year.to_string().as_str(),
+        ))?);
+    }
+    if let Some((year, _)) = last {
+        filters.push(PartitionFilter::try_from((
+            "capture_year",
+            "<=",
+            year.to_string().as_str(),
+        ))?);
+    }
+    // months only bound the partitions of a single year
+    if let (Some((first_year, first_month)), Some((last_year, last_month))) = (first, last)
+        && first_year == last_year
+    {
+        filters.push(PartitionFilter::try_from((
+            "capture_month",
+            ">=",
+            first_month.to_string().as_str(),
+        ))?);
+        filters.push(PartitionFilter::try_from((
+            "capture_month",
+            "<=",
+            last_month.to_string().as_str(),
+        ))?);
+    }
+    Ok(filters)
+}
 
-    pub struct DeltaReader {
-        table: DeltaTable,
+/// Rows of `table` at `as_of`, the latest version by default, in the
+/// partitions `partitions` selects. `None` while there are no such files.
+fn scan(
+    table: &mut DeltaTable,
+    as_of: Option<AsOf>,
+    partitions: &[PartitionFilter],
+) -> Result<Option<LazyFrame>> {
+    let runtime = deltalake::storage::IORuntime::default().get_handle();
+    match as_of {
+        None => runtime.block_on(table.update())?,
+        Some(AsOf::Version(version)) => runtime.block_on(table.load_version(version))?,
+        Some(AsOf::Time(time)) => runtime.block_on(table.load_with_datetime(chrono_time(time)?))?,
     }
-    pub struct DeltaWriter {
-        queue: vec::Vec<Picture>,
-        schema: Schema,
-        table: DeltaTable,
+
+    let files: Vec<PathBuf> = match partitions.is_empty() {
+        true => table.get_file_uris()?.map(PathBuf::from).collect(),
+        false => table
+            .get_file_uris_by_partitions(partitions)?
+            .into_iter()
+            .map(PathBuf::from)
+            .collect(),
+    };
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let frame = LazyFrame::scan_parquet_files(files.into(), ScanArgsParquet::default())?;
+    Ok(Some(frame))
+}
+
+/// Microseconds of a timestamp column.
+fn micros(column: &str) -> Expr {
+    col(column).cast(polars::prelude::DataType::Int64)
+}
+
+/// Column holding the sort key of `order_by`.
+fn key_column(order_by: OrderBy) -> Expr {
+    match order_by {
+        OrderBy::FsCreateTime => micros("fs_create_time"),
+        OrderBy::FsModifyTime => micros("fs_modify_time"),
+        OrderBy::ExifCreateTime => micros("exif_create_time"),
+        OrderBy::Path => col("path"),
     }
-    use polars::prelude::*;
+}
+
+impl StoreReader for DeltaReader {
+    fn load(
+        &mut self,
+        sort: Sort,
+        filter: Filter,
+        limit: usize,
+    ) -> Result<impl Iterator<Item = BasicPicture>> {
+        if let Some(after) = &filter.after
+            && after.sort != sort
+        {
+            bail!("cursor of {} used to list by {sort}", after.sort);
+        }
+        let Some(frame) = scan(&mut self.table, filter.as_of, &partition_filters(&filter)?)? else {
+            return Ok(vec![].into_iter());
+        };
 
-    pub struct DeltaResult {
-        dataframe: DataFrame,
-        row: usize,
-        error_count: usize,
+        let mut frame = frame.filter(filter_expr(&filter)?).sort_by_exprs(
+            sort_exprs(&sort),
+            SortMultipleOptions::default()
+                .with_order_descending_multi(
+                    sort.0
+                        .iter()
+                        .map(|key| key.descending)
+                        .chain([sort.descending_id()]),
+                )
+                .with_nulls_last(true),
+        );
+        if limit != 0 {
+            frame = frame.limit(limit.try_into().unwrap_or(IdxSize::MAX));
+        }
+
+        Ok(pictures(frame.collect()?)?.into_iter())
     }
 
-    impl Iterator for DeltaResult {
-        type Item = Picture;
+    /// Counted by polars per quarter hour and time zone, which fall on one
+    /// day of the zone, only the partitions of an exif time range are read.
+    fn timeline(
+        &mut self,
+        key: OrderBy,
+        granularity: Granularity,
+        filter: Filter,
+    ) -> Result<Vec<TimelineBucket>> {
+        let column = match key {
+            OrderBy::FsCreateTime => "fs_create_time",
+            OrderBy::FsModifyTime => "fs_modify_time",
+            OrderBy::ExifCreateTime => "exif_create_time",
+            OrderBy::Path => bail!("path is not a time"),
+        };
+        let Some(frame) = scan(&mut self.table, filter.as_of, &partition_filters(&filter)?)? else {
+            return Ok(vec![]);
+        };
+        let zone_column = format!("{column}_timezone");
+        // the start of the quarter hour, also for times before the epoch
+        let quarter = lit(QUARTER_HOUR_MICROS);
+        let past_quarter = (micros(column) % quarter.clone() + quarter.clone()) % quarter;
+        let groups = frame
+            .filter(filter_expr(&filter)?.and(col(column).is_not_null()))
+            .group_by([
+                (micros(column) - past_quarter).alias("quarter"),
+                col(zone_column.as_str()),
+            ])
+            .agg([polars_lazy::prelude::len().alias("count")])
+            .collect()?;
+
+        let column = |name: &str| -> Result<Series> {
+            Ok(groups.column(name)?.as_materialized_series().clone())
+        };
+        let (quarters, zones) = (column("quarter")?, column(&zone_column)?);
+        let counts = column("count")?.cast(&polars::prelude::DataType::UInt64)?;
+        let (quarters, zones, counts) = (quarters.i64()?, zones.str()?, counts.u64()?);
 
-        fn next(&mut self) -> Option<Self::Item> {
-            if self.row >= self.dataframe.height() {
-                return None;
+        let mut buckets: BTreeMap<jiff::civil::Date, u64> = BTreeMap::new();
+        for i in 0..groups.height() {
+            if let Some(time) = zoned(quarters.get(i), zones.get(i)) {
+                *buckets.entry(granularity.start(time.0.date())).or_default() +=
+                    counts.get(i).unwrap_or(0);
             }
+        }
 
-            while self.error_count < 10 {
-                match self.dataframe.get_row(self.row) {
-                    Ok(row_content) => {
-                        if let AnyValue::String(s) = row_content.0[0] {
-                            return Some(Picture::new(s));
-                        }
-                    }
-                    _ => {}
-                }
-                self.error_count += 1;
+        Ok(buckets
+            .into_iter()
+            .map(|(start, count)| TimelineBucket { start, count })
+            .collect())
+    }
+}
+
+/// Time zone offsets and their transitions fall on whole quarter hours.
+const QUARTER_HOUR_MICROS: i64 = 15 * 60 * 1_000_000;
+
+/// Rows of `frame` as pictures, skipping those which don't read back like
+/// `SqliteResult` does.
+fn pictures(frame: DataFrame) -> Result<Vec<BasicPicture>> {
+    let column =
+        |name: &str| -> Result<Series> { Ok(frame.column(name)?.as_materialized_series().clone()) };
+    let time = |name: &str| -> Result<Series> {
+        Ok(column(name)?.cast(&polars::prelude::DataType::Int64)?)
+    };
+    let ids = column("id")?;
+    let paths = column("path")?;
+    let media_types = column("media_type")?;
+    let fs_create_times = time("fs_create_time")?;
+    let fs_create_time_zones = column("fs_create_time_timezone")?;
+    let fs_create_time_sources = column("fs_create_time_source")?;
+    let fs_modify_times = time("fs_modify_time")?;
+    let fs_modify_time_zones = column("fs_modify_time_timezone")?;
+    let exif_create_times = time("exif_create_time")?;
+    let exif_create_time_zones = column("exif_create_time_timezone")?;
+
+    let (ids, paths, media_types) = (ids.i64()?, paths.binary()?, media_types.str()?);
+    let (fs_create_times, fs_create_time_zones, fs_create_time_sources) = (
+        fs_create_times.i64()?,
+        fs_create_time_zones.str()?,
+        fs_create_time_sources.str()?,
+    );
+    let (fs_modify_times, fs_modify_time_zones) =
+        (fs_modify_times.i64()?, fs_modify_time_zones.str()?);
+    let (exif_create_times, exif_create_time_zones) =
+        (exif_create_times.i64()?, exif_create_time_zones.str()?);
+
+    let picture = |i: usize| -> Option<BasicPicture> {
+        Some(BasicPicture {
+            id: ids.get(i)?,
+            path: path_from_bytes(paths.get(i)?.to_vec()),
+            media_type: MediaType::from_str(media_types.get(i)?).ok()?,
+            fs_create_time: zoned(fs_create_times.get(i), fs_create_time_zones.get(i))?,
+            fs_create_time_source: TimeSource::from_str(fs_create_time_sources.get(i)?).ok()?,
+            fs_modify_time: zoned(fs_modify_times.get(i), fs_modify_time_zones.get(i))?,
+            exif_create_time: zoned(exif_create_times.get(i), exif_create_time_zones.get(i)),
+        })
+    };
+
+    Ok((0..frame.height()).filter_map(picture).collect())
+}
+
+/// A time read back from its microseconds and time zone columns.
+fn zoned(micros: Option<i64>, zone: Option<&str>) -> Option<Zoned> {
+    let timestamp = jiff::Timestamp::from_microsecond(micros?).ok()?;
+    Some(Zoned(
+        timestamp.to_zoned(time_zone_from_string(zone?).ok()?),
+    ))
+}
+
+/// What a version of the table did to a record.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Change {
+    Insert,
+    Update,
+    /// Marked missing, see `MissingPolicy::Mark`.
+    Missing,
+    Delete,
+}
+
+/// A record as a version left it, as it was before for a `Change::Delete`.
+#[derive(Serialize)]
+pub struct ChangeEvent {
+    pub version: i64,
+    pub change: Change,
+    pub picture: BasicPicture,
+}
+
+/// Changes made by the versions of the table at `uri` from `from` to `to`
+/// included, up to the latest without `to`, oldest first. Read from the
+/// change data feed, which versions written before it was enabled lack.
+pub fn changes(uri: &str, from: i64, to: Option<i64>) -> Result<Vec<ChangeEvent>> {
+    let runtime = deltalake::storage::IORuntime::default().get_handle();
+    let table = runtime.block_on(deltalake::DeltaTableBuilder::from_uri(uri).load())?;
+
+    let mut cdf = DeltaOps(table).load_cdf().with_starting_version(from);
+    if let Some(to) = to {
+        cdf = cdf.with_ending_version(to);
+    }
+    let frame = SessionContext::new().read_table(Arc::new(DeltaCdfTableProvider::try_new(cdf)?))?;
+
+    let mut events = vec![];
+    for batch in runtime.block_on(frame.collect())? {
+        events.extend(change_events(&batch)?);
+    }
+    events.sort_by_key(|event| event.version);
+    Ok(events)
+}
+
+/// Rows of a change data feed `batch` as events, leaving out the images of
+/// updated rows before the update and rows which don't read back.
+fn change_events(batch: &RecordBatch) -> Result<Vec<ChangeEvent>> {
+    // reads may hand out view or dictionary types, cast to the written ones
+    let column = |name: &str, data_type: DataType| -> Result<ArrayRef> {
+        let array = batch
+            .column_by_name(name)
+            .ok_or_else(|| anyhow!("change data feed has no {name}"))?;
+        Ok(arrow::compute::cast(array, &data_type)?)
+    };
+    let ids = column("id", DataType::Int64)?;
+    let paths = column("path", DataType::Binary)?;
+    let media_types = column("media_type", DataType::Utf8)?;
+    let fs_create_times = column("fs_create_time", DataType::Int64)?;
+    let fs_create_time_zones = column("fs_create_time_timezone", DataType::Utf8)?;
+    let fs_create_time_sources = column("fs_create_time_source", DataType::Utf8)?;
+    let fs_modify_times = column("fs_modify_time", DataType::Int64)?;
+    let fs_modify_time_zones = column("fs_modify_time_timezone", DataType::Utf8)?;
+    let exif_create_times = column("exif_create_time", DataType::Int64)?;
+    let exif_create_time_zones = column("exif_create_time_timezone", DataType::Utf8)?;
+    let missings = column("missing", DataType::Boolean)?;
+    let change_types = column("_change_type", DataType::Utf8)?;
+    let versions = column("_commit_version", DataType::Int64)?;
+
+    let int = |array: &ArrayRef, i: usize| -> Option<i64> {
+        let array = array.as_primitive::<Int64Type>();
+        array.is_valid(i).then(|| array.value(i))
+    };
+    let string = |array: &ArrayRef, i: usize| -> Option<String> {
+        let array = array.as_string::<i32>();
+        array.is_valid(i).then(|| array.value(i).to_owned())
+    };
+
+    let event = |i: usize| -> Option<ChangeEvent> {
+        let missing = missings.as_boolean();
+        let change = match string(&change_types, i)?.as_str() {
+            "insert" => Change::Insert,
+            "update_postimage" if missing.is_valid(i) && missing.value(i) => Change::Missing,
+            "update_postimage" => Change::Update,
+            "delete" => Change::Delete,
+            _ => return None,
+        };
+        let paths = paths.as_binary::<i32>();
+        let picture = BasicPicture {
+            id: int(&ids, i)?,
+            path: path_from_bytes(paths.is_valid(i).then(|| paths.value(i).to_vec())?),
+            media_type: MediaType::from_str(&string(&media_types, i)?).ok()?,
+            fs_create_time: zoned(
+                int(&fs_create_times, i),
+                string(&fs_create_time_zones, i).as_deref(),
+            )?,
+            fs_create_time_source: TimeSource::from_str(&string(&fs_create_time_sources, i)?)
+                .ok()?,
+            fs_modify_time: zoned(
+                int(&fs_modify_times, i),
+                string(&fs_modify_time_zones, i).as_deref(),
+            )?,
+            exif_create_time: zoned(
+                int(&exif_create_times, i),
+                string(&exif_create_time_zones, i).as_deref(),
+            ),
+        };
+        Some(ChangeEvent {
+            version: int(&versions, i)?,
+            change,
+            picture,
+        })
+    };
+
+    Ok((0..batch.num_rows()).filter_map(event).collect())
+}
+
+/// Id of a newly inserted record. It has to survive moves so it can't be
+/// derived from the path alone, and delta has no sequences to hand it out.
+fn new_id(record: &PictureRecord) -> i64 {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(path_bytes(&record.path));
+    hasher.update(&jiff::Timestamp::now().as_nanosecond().to_le_bytes());
+    let bytes: [u8; 8] = hasher.finalize().as_bytes()[..8]
+        .try_into()
+        .expect("8 bytes");
+    // positive like sqlite rowids
+    (u64::from_le_bytes(bytes) >> 1) as i64
+}
+
+/// Length of the run of ops at the head of `ops` which one commit applies,
+/// ops of one kind. A move touching a path an earlier move of the run did
+/// starts the next run, as they apply one after the other.
+fn run_len(ops: &[FsOp]) -> usize {
+    let kind = std::mem::discriminant(&ops[0]);
+    let mut moved: HashSet<&Path> = HashSet::new();
+    ops.iter()
+        .position(|op| match op {
+            _ if std::mem::discriminant(op) != kind => true,
+            FsOp::Move { from, to, .. } => {
+                let touched = moved.contains(from.as_path()) || moved.contains(to.as_path());
+                moved.extend([from.as_path(), to.as_path()]);
+                touched
             }
-            None
+            _ => false,
+        })
+        .unwrap_or(ops.len())
+}
+
+/// Paths as a sql list for delta predicates.
+fn sql_binaries<'a>(paths: impl Iterator<Item = &'a Path>) -> String {
+    paths.map(sql_binary).collect::<Vec<_>>().join(", ")
+}
+
+impl DeltaWriter {
+    /// `records` as rows, keeping the last record of each path as a merge
+    /// takes at most one source row per target row.
+    fn batch(&self, records: &[&PictureRecord]) -> Result<RecordBatch> {
+        let mut last: HashMap<&Path, usize> = HashMap::new();
+        for (i, record) in records.iter().enumerate() {
+            last.insert(&record.path, i);
+        }
+        let records: Vec<&PictureRecord> = records
+            .iter()
+            .enumerate()
+            .filter(|(i, record)| last[record.path.as_path()] == *i)
+            .map(|(_, record)| *record)
+            .collect();
+
+        let micros = |zoned: &Zoned| zoned.0.timestamp().as_microsecond();
+        let utc = |values: Vec<Option<i64>>| -> ArrayRef {
+            Arc::new(TimestampMicrosecondArray::from(values).with_timezone("UTC"))
+        };
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from_iter_values(
+                records.iter().map(|r| new_id(r)),
+            )),
+            Arc::new(BinaryArray::from_iter_values(
+                records.iter().map(|r| path_bytes(&r.path)),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                records.iter().map(|r| r.media_type.as_ref()),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                records.iter().map(|r| r.size as i64),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                records.iter().map(|r| r.inode as i64),
+            )),
+            utc(records
+                .iter()
+                .map(|r| Some(micros(&r.fs_create_time)))
+                .collect()),
+            Arc::new(StringArray::from_iter_values(
+                records
+                    .iter()
+                    .map(|r| time_zone_string(&r.fs_create_time.0)),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                records.iter().map(|r| r.fs_create_time_source.as_ref()),
+            )),
+            utc(records
+                .iter()
+                .map(|r| Some(micros(&r.fs_modify_time)))
+                .collect()),
+            Arc::new(StringArray::from_iter_values(
+                records
+                    .iter()
+                    .map(|r| time_zone_string(&r.fs_modify_time.0)),
+            )),
+            utc(records
+                .iter()
+                .map(|r| r.exif_create_time.as_ref().map(micros))
+                .collect()),
+            Arc::new(StringArray::from_iter(records.iter().map(|r| {
+                r.exif_create_time.as_ref().map(|t| time_zone_string(&t.0))
+            }))),
+            Arc::new(BinaryArray::from_iter_values(
+                records.iter().map(|r| r.content_hash.as_slice()),
+            )),
+            Arc::new(Int64Array::from_iter(
+                records.iter().map(|r| r.perceptual_hash.map(|h| h as i64)),
+            )),
+            Arc::new(BooleanArray::from(vec![false; records.len()])),
+            Arc::new(Int32Array::from_iter_values(
+                records.iter().map(|r| capture_month(r).0),
+            )),
+            Arc::new(Int32Array::from_iter_values(
+                records.iter().map(|r| capture_month(r).1),
+            )),
+        ];
+
+        Ok(RecordBatch::try_new(
+            Arc::new(self.schema.clone()),
+            columns,
+        )?)
+    }
+
+    /// Upserts by path like sqlite, a record rewritten keeps its id.
+    fn upsert(&self, records: &[&PictureRecord]) -> Result<DeltaTable> {
+        let source = SessionContext::new().read_batch(self.batch(records)?)?;
+        let columns: Vec<String> = self
+            .schema
+            .fields()
+            .iter()
+            .map(|f| f.name().to_owned())
+            .collect();
+
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let (table, _) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .merge(source, "target.path = source.path")
+                .with_source_alias("source")
+                .with_target_alias("target")
+                .when_matched_update(|update| {
+                    columns
+                        .iter()
+                        .filter(|column| *column != "id")
+                        .fold(update, |update, column| {
+                            update.update(column.as_str(), format!("source.{column}"))
+                        })
+                })?
+                .when_not_matched_insert(|insert| {
+                    columns.iter().fold(insert, |insert, column| {
+                        insert.set(column.as_str(), format!("source.{column}"))
+                    })
+                })?
+                .into_future(),
+        )?;
+        Ok(table)
+    }
+
+    fn remove(&self, paths: &[&Path]) -> Result<DeltaTable> {
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let (table, _) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .delete()
+                .with_predicate(format!("path IN ({})", sql_binaries(paths.iter().copied())))
+                .into_future(),
+        )?;
+        Ok(table)
+    }
+
+    /// A merge rather than an update, the update of delta 0.25 writes its
+    /// predicate column into the data files when the change feed is on.
+    fn mark_missing(&self, paths: &[&Path]) -> Result<DeltaTable> {
+        // a merge takes at most one source row per target row
+        let paths: HashSet<&Path> = paths.iter().copied().collect();
+        let schema = Schema::new(vec![Field::new("path", DataType::Binary, false)]);
+        let columns: Vec<ArrayRef> = vec![Arc::new(BinaryArray::from_iter_values(
+            paths.iter().map(|path| path_bytes(path)),
+        ))];
+        let source =
+            SessionContext::new().read_batch(RecordBatch::try_new(Arc::new(schema), columns)?)?;
+
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let (table, _) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .merge(source, "target.path = source.path")
+                .with_source_alias("source")
+                .with_target_alias("target")
+                .when_matched_update(|update| update.update("missing", "true"))?
+                .into_future(),
+        )?;
+        Ok(table)
+    }
+
+    /// Moves of paths no other move of `moves` touches, each replacing any
+    /// record already at its `to`, like `update or replace` does. One merge
+    /// matches the record to move by `from` and the one to replace by `to`.
+    fn apply_moves(&self, moves: &[(&Path, &Path, &FileIdentity)]) -> Result<DeltaTable> {
+        let rows: Vec<(&Path, Option<(&Path, &FileIdentity)>)> = moves
+            .iter()
+            .flat_map(|&(from, to, identity)| [(from, Some((to, identity))), (to, None)])
+            .filter(|(path, moved)| moved.is_some() || moves.iter().all(|(from, ..)| from != path))
+            .collect();
+        let schema = Schema::new(vec![
+            Field::new("path", DataType::Binary, false),
+            Field::new("new_path", DataType::Binary, true),
+            Field::new("size", DataType::Int64, true),
+            Field::new("inode", DataType::Int64, true),
+            Field::new(
+                "fs_modify_time",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                true,
+            ),
+            Field::new("replaced", DataType::Boolean, false),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(BinaryArray::from_iter_values(
+                rows.iter().map(|(path, _)| path_bytes(path)),
+            )),
+            Arc::new(BinaryArray::from_iter(
+                rows.iter()
+                    .map(|(_, moved)| moved.map(|(to, _)| path_bytes(to))),
+            )),
+            Arc::new(Int64Array::from_iter(rows.iter().map(|(_, moved)| {
+                moved.map(|(_, identity)| identity.size as i64)
+            }))),
+            Arc::new(Int64Array::from_iter(rows.iter().map(|(_, moved)| {
+                moved.map(|(_, identity)| identity.inode as i64)
+            }))),
+            Arc::new(
+                TimestampMicrosecondArray::from(
+                    rows.iter()
+                        .map(|(_, moved)| {
+                            moved.map(|(_, identity)| identity.modify_time.as_microsecond())
+                        })
+                        .collect::<Vec<_>>(),
+                )
+                .with_timezone("UTC"),
+            ),
+            Arc::new(BooleanArray::from(
+                rows.iter()
+                    .map(|(_, moved)| moved.is_none())
+                    .collect::<Vec<_>>(),
+            )),
+        ];
+        let source =
+            SessionContext::new().read_batch(RecordBatch::try_new(Arc::new(schema), columns)?)?;
+
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        let (table, _) = runtime.block_on(
+            DeltaOps(self.table.clone())
+                .merge(source, "target.path = source.path")
+                .with_source_alias("source")
+                .with_target_alias("target")
+                .when_matched_delete(|delete| delete.predicate("source.replaced"))?
+                .when_matched_update(|update| {
+                    update
+                        .update("path", "source.new_path")
+                        .update("size", "source.size")
+                        .update("inode", "source.inode")
+                        .update("fs_modify_time", "source.fs_modify_time")
+                        .update("missing", "false")
+                })?
+                .into_future(),
+        )?;
+        Ok(table)
+    }
+
+    fn push(&mut self, op: FsOp) -> Result<()> {
+        self.queue.push(op);
+
+        if self.queue.len() > 1000 {
+            self.flush()?
         }
+
+        Ok(())
     }
+}
 
-    impl StoreReader for DeltaReader {
-        fn load(
-            &mut self,
-            orderr_by: OrderBy,
-            limit: usize,
-        ) -> Result<impl Iterator<Item = Picture>> {
-            let runtime = deltalake::storage::IORuntime::default().get_handle();
-            runtime.block_on(self.table.update()).expect("update ok");
+/// Ops still queued are written rather than lost, but callers should flush
+/// to see the failure.
+impl Drop for DeltaWriter {
+    fn drop(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        rocket::warn!("{} delta ops were not flushed", self.queue.len());
+        if let Err(e) = self.flush() {
+            rocket::error!("flushing the delta ops on drop failed: {e:#}");
+        }
+    }
+}
 
-            let empty = DeltaResult {
-                dataframe: DataFrame::default(),
-                row: 0,
-                error_count: 0,
-            };
+impl FsOpCallback for DeltaWriter {
+    fn on_op(&mut self, entry: PictureRecord) -> Result<()> {
+        self.push(FsOp::Upsert(Box::new(entry)))
+    }
 
-            let Ok(files) = self.table.get_file_uris() else {
-                return Ok(empty);
+    /// Applies each run of ops of one kind with one commit. The ops of runs
+    /// which failed and those after are kept queued.
+    fn flush(&mut self) -> Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+        // on top of what other writers committed since this one was made
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime.block_on(self.table.update())?;
+
+        while !self.queue.is_empty() {
+            let len = run_len(&self.queue);
+            let run = &self.queue[..len];
+            let paths = || -> Vec<&Path> { run.iter().map(FsOp::path).collect() };
+            self.table = match &run[0] {
+                FsOp::Upsert(_) => {
+                    let records: Vec<&PictureRecord> = run
+                        .iter()
+                        .filter_map(|op| match op {
+                            FsOp::Upsert(record) => Some(record.as_ref()),
+                            _ => None,
+                        })
+                        .collect();
+                    self.upsert(&records)?
+                }
+                FsOp::Remove(_) => self.remove(&paths())?,
+                FsOp::Missing(_) => self.mark_missing(&paths())?,
+                FsOp::Move { .. } => {
+                    let moves: Vec<_> = run
+                        .iter()
+                        .filter_map(|op| match op {
+                            FsOp::Move { from, to, identity } => {
+                                Some((from.as_path(), to.as_path(), identity))
+                            }
+                            _ => None,
+                        })
+                        .collect();
+                    self.apply_moves(&moves)?
+                }
             };
+            self.queue.drain(..len);
+        }
+        Ok(())
+    }
+
+    fn stored_identities(&mut self, root: &Path) -> Result<HashMap<PathBuf, FileIdentity>> {
+        self.flush()?;
 
-            let vec: Vec<PathBuf> = files.map(|x| PathBuf::from(x)).collect();
-            let a = vec.into_boxed_slice();
+        let Some(frame) = scan(&mut self.table, None, &[])? else {
+            return Ok(HashMap::new());
+        };
+        let filter = Filter {
+            path_prefix: Some(root.to_owned()),
+            ..Default::default()
+        };
+        let frame = frame
+            .filter(filter_expr(&filter)?)
+            .select([
+                col("path"),
+                col("size"),
+                col("inode"),
+                micros("fs_modify_time"),
+            ])
+            .collect()?;
 
-            let Ok(df) = LazyFrame::scan_parquet_files(a.into(), ScanArgsParquet::default()) else {
-                return Ok(empty);
+        let column = |name: &str| -> Result<Series> {
+            Ok(frame.column(name)?.as_materialized_series().clone())
+        };
+        let (paths, sizes, inodes, modify_times) = (
+            column("path")?,
+            column("size")?,
+            column("inode")?,
+            column("fs_modify_time")?,
+        );
+        let (paths, sizes, inodes, modify_times) = (
+            paths.binary()?,
+            sizes.i64()?,
+            inodes.i64()?,
+            modify_times.i64()?,
+        );
+
+        let mut ret = HashMap::new();
+        for i in 0..frame.height() {
+            let (Some(path), Some(size), Some(inode), Some(modify_time)) = (
+                paths.get(i),
+                sizes.get(i),
+                inodes.get(i),
+                modify_times.get(i),
+            ) else {
+                continue;
             };
-            let Ok(dataframe) = df.collect() else {
-                return Ok(empty);
+            let Ok(modify_time) = jiff::Timestamp::from_microsecond(modify_time) else {
+                continue;
             };
+            ret.insert(
+                path_from_bytes(path.to_vec()),
+                FileIdentity {
+                    size: size as u64,
+                    inode: inode as u64,
+                    modify_time,
+                },
+            );
+        }
+        Ok(ret)
+    }
 
-            Ok(DeltaResult {
-                dataframe,
-                row: 0,
-                error_count: 0,
-            })
+    fn stored_content_hash(&mut self, path: &Path) -> Result<Option<[u8; 32]>> {
+        // flushing is a commit, only taken when the queue changes the record
+        let queued = self.queue.iter().any(|op| match op {
+            FsOp::Move { from, to, .. } => from == path || to == path,
+            op => op.path() == path,
+        });
+        if queued {
+            self.flush()?;
         }
+
+        let Some(frame) = scan(&mut self.table, None, &[])? else {
+            return Ok(None);
+        };
+        let frame = frame
+            .filter(col("path").eq(lit(path_bytes(path).to_vec())))
+            .select([col("content_hash")])
+            .collect()?;
+        let hashes = frame
+            .column("content_hash")?
+            .as_materialized_series()
+            .clone();
+        let hash = hashes.binary()?.get(0).map(|hash| hash.to_vec());
+        Ok(hash.and_then(|hash| hash.try_into().ok()))
     }
 
-    impl FsOpCallback for DeltaWriter {
-        fn on_op(&mut self, entry: Picture) -> Result<()> {
-            self.queue.push(entry);
+    fn on_remove(&mut self, path: &Path) -> Result<()> {
+        self.push(FsOp::Remove(path.to_owned()))
+    }
 
-            if self.queue.len() > 1000 {
-                self.flush()?
-            }
+    fn on_missing(&mut self, path: &Path) -> Result<()> {
+        self.push(FsOp::Missing(path.to_owned()))
+    }
 
-            Ok(())
-        }
-        fn flush(&mut self) -> Result<()> {
-            if self.queue.is_empty() {
-                return Ok(());
+    fn on_move(&mut self, from: &Path, to: &Path, identity: FileIdentity) -> Result<()> {
+        self.push(FsOp::Move {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            identity,
+        })
+    }
+}
+
+/// Columns ordered by `sort`, then the id breaking ties.
+fn sort_exprs(sort: &Sort) -> Vec<Expr> {
+    let mut exprs: Vec<Expr> = sort.0.iter().map(|key| key_column(key.order_by)).collect();
+    exprs.push(col("id"));
+    exprs
+}
+
+/// Rows listed after `after`, see `after_condition` of the sqlite store.
+/// Missing times sort last whichever the direction.
+fn after_expr(after: &Cursor) -> Result<Expr> {
+    let mut alternatives = vec![];
+    let mut equal = lit(true);
+    for (key, value) in after.sort.0.iter().zip(&after.values) {
+        let column = key_column(key.order_by);
+        let value = match (key.order_by, value) {
+            (OrderBy::Path, SortValue::Path(path)) => lit(path_bytes(path).to_vec()),
+            (_, SortValue::Time(Some(time))) if key.order_by != OrderBy::Path => {
+                lit(time.as_microsecond())
+            }
+            (_, SortValue::Time(None)) if key.order_by != OrderBy::Path => {
+                equal = equal.and(column.is_null());
+                continue;
             }
+            _ => bail!("cursor values don't match its sort"),
+        };
+        let later = match key.descending {
+            true => column.clone().lt(value.clone()),
+            false => column.clone().gt(value.clone()),
+        };
+        alternatives.push(equal.clone().and(column.clone().is_null().or(later)));
+        equal = equal.and(column.eq(value));
+    }
+    alternatives.push(equal.and(match after.sort.descending_id() {
+        true => col("id").lt(lit(after.id)),
+        false => col("id").gt(lit(after.id)),
+    }));
 
-            let vec: vec::Vec<String> = self
-                .queue
-                .iter()
-                .map(|entry| entry.path().to_string_lossy().into_owned())
-                .collect();
+    Ok(alternatives
+        .into_iter()
+        .reduce(|any, alternative| any.or(alternative))
+        .expect("the id alternative"))
+}
 
-            let string_array = StringArray::from(vec);
+/// `filter` as a polars predicate.
+fn filter_expr(filter: &Filter) -> Result<Expr> {
+    let mut expr = col("missing").not();
 
-            let batch =
-                RecordBatch::try_new(Arc::new(self.schema.clone()), vec![Arc::new(string_array)])
-                    .unwrap();
+    if !filter.media_types.is_empty() {
+        let any = filter
+            .media_types
+            .iter()
+            .map(|media_type| col("media_type").eq(lit(media_type.as_ref())))
+            .reduce(|any, one| any.or(one))
+            .expect("not empty");
+        expr = expr.and(any);
+    }
+    if let Some(ids) = &filter.ids {
+        let any = ids
+            .iter()
+            .map(|id| col("id").eq(lit(*id)))
+            .reduce(|any, one| any.or(one))
+            .unwrap_or(lit(false));
+        expr = expr.and(any);
+    }
+    if let Some(after) = &filter.after {
+        expr = expr.and(after_expr(after)?);
+    }
+    if let Some(range) = &filter.time_range {
+        if range.key == OrderBy::Path {
+            bail!("path is not a time");
+        }
+        let column = key_column(range.key);
+        expr = expr.and(column.clone().is_not_null());
+        if let Some(since) = range.since {
+            expr = expr.and(column.clone().gt_eq(lit(since.as_microsecond())));
+        }
+        if let Some(until) = range.until {
+            expr = expr.and(column.lt(lit(until.as_microsecond())));
+        }
+    }
+    if let Some(prefix) = &filter.path_prefix {
+        expr = expr.and(
+            col("path")
+                .eq(lit(path_bytes(prefix).to_vec()))
+                .or(col("path").binary().starts_with(lit(below(prefix)))),
+        );
+    }
+    if let Some(glob) = &filter.path_glob {
+        // the bytes of a path, which polars has no pattern matching for
+        let glob: Vec<u32> = glob.chars().map(u32::from).collect();
+        expr = expr.and(col("path").map(
+            move |paths| {
+                let matched: BooleanChunked = paths
+                    .as_materialized_series()
+                    .binary()?
+                    .into_iter()
+                    .map(|path| path.map(|path| glob_matches(&glob, &sqlite_chars(path))))
+                    .collect();
+                Ok(Some(matched.with_name(paths.name().clone()).into_column()))
+            },
+            GetOutput::from_type(polars::prelude::DataType::Boolean),
+        ));
+    }
+    if let Some(min_size) = filter.min_size {
+        let min_size = i64::try_from(min_size).unwrap_or(i64::MAX);
+        expr = expr.and(col("size").gt_eq(lit(min_size)));
+    }
+    if let Some(max_size) = filter.max_size {
+        let max_size = i64::try_from(max_size).unwrap_or(i64::MAX);
+        expr = expr.and(col("size").lt_eq(lit(max_size)));
+    }
+    Ok(expr)
+}
 
-            let runtime = deltalake::storage::IORuntime::default().get_handle();
+/// Code points of `bytes` as sqlite reads text, which needn't be UTF-8: a
+/// lead byte takes the continuation bytes after it, overlong or surrogate
+/// sequences read as U+FFFD and stray bytes as themselves.
+fn sqlite_chars(bytes: &[u8]) -> Vec<u32> {
+    let mut chars = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let lead = bytes[i];
+        i += 1;
+        if lead < 0xC0 {
+            chars.push(lead.into());
+            continue;
+        }
+        let mut c = u32::from(match lead {
+            0xC0..=0xDF => lead & 0x1F,
+            0xE0..=0xEF => lead & 0x0F,
+            0xF0..=0xF7 => lead & 0x07,
+            0xF8..=0xFB => lead & 0x03,
+            0xFC..=0xFD => lead & 0x01,
+            _ => 0,
+        });
+        while i < bytes.len() && bytes[i] & 0xC0 == 0x80 {
+            c = c.wrapping_shl(6).wrapping_add(u32::from(bytes[i] & 0x3F));
+            i += 1;
+        }
+        if c < 0x80 || c & 0xFFFF_F800 == 0xD800 || c & 0xFFFF_FFFE == 0xFFFE {
+            c = 0xFFFD;
+        }
+        chars.push(c);
+    }
+    chars
+}
 
-            runtime.block_on(DeltaOps(self.table.clone()).write([batch]).into_future())?;
-            self.queue.clear();
-            Ok(())
+/// Whether `path` matches a `Filter::path_glob` as sqlite's `glob` has it,
+/// both as code points.
+fn glob_matches(glob: &[u32], path: &[u32]) -> bool {
+    let Some((&c, rest)) = glob.split_first() else {
+        return path.is_empty();
+    };
+    match char::from_u32(c) {
+        Some('*') => (0..=path.len()).any(|skip| glob_matches(rest, &path[skip..])),
+        Some('?') => !path.is_empty() && glob_matches(rest, &path[1..]),
+        Some('[') => match path.split_first() {
+            Some((&first, path)) => match glob_class(rest, first) {
+                Some((true, rest)) => glob_matches(rest, path),
+                _ => false,
+            },
+            None => false,
+        },
+        _ => path.first() == Some(&c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+/// Whether `c` is in the glob class opening `glob`, just after its `[`, and
+/// the glob after the class. A `]` right after the `[` or `[^` is part of
+/// the class. `None` when unclosed, sqlite then matches nothing.
+fn glob_class(glob: &[u32], c: u32) -> Option<(bool, &[u32])> {
+    let is = |i: usize, ch: char| glob.get(i) == Some(&u32::from(ch));
+    let mut i = 0;
+    let invert = is(i, '^');
+    if invert {
+        i += 1;
+    }
+    let mut seen = false;
+    if is(i, ']') {
+        seen = c == u32::from(']');
+        i += 1;
+    }
+    let mut prior = None;
+    loop {
+        let &member = glob.get(i)?;
+        i += 1;
+        if member == u32::from(']') {
+            return Some((seen != invert, &glob[i..]));
+        }
+        match prior {
+            Some(low) if member == u32::from('-') && glob.get(i).is_some() && !is(i, ']') => {
+                seen |= (low..=glob[i]).contains(&c);
+                i += 1;
+                prior = None;
+            }
+            _ => {
+                seen |= c == member;
+                prior = Some(member);
+            }
         }
     }
 }
+
+/// `path` as a sql binary literal for delta predicates.
+fn sql_binary(path: &Path) -> String {
+    let hex: String = path_bytes(path)
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect();
+    format!("X'{hex}'")
+}
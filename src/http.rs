@@ -1,22 +1,41 @@
-use std::{path::PathBuf, sync::Arc};
+use std::collections::HashMap;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
 
-use rocket::response::stream::TextStream;
-use rocket::{State, get, request::FromParam};
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, stream::TextStream};
+use rocket::{FromForm, Request, State, get, request::FromParam};
 
 extern crate rocket;
 
 pub struct ServerConfig {
     pub store_path: Arc<PathBuf>,
+    pub similar: Arc<Mutex<SqliteSimilarIndex>>,
+    /// Uri of the delta table kept next to the index, which `/versions` and
+    /// `/v1/changes` read.
+    pub delta_table: Option<String>,
 }
 
-use crate::common::{BasicPicture, OrderBy, StoreReader};
-use crate::sqlite::{SaveToSqlite, SqliteReader};
+use crate::common::{
+    BasicPicture, Cursor, Filter, Granularity, OrderBy, Sort, StoreReader, TimeRange,
+};
+#[cfg(feature = "delta")]
+use crate::delta;
+use crate::media::MediaType;
+use crate::sqlite::{SaveToSqlite, SqliteReader, SqliteSimilarIndex};
+use crate::v1;
+use serde::Serialize;
 
 impl ServerConfig {
     pub fn new(path: PathBuf) -> anyhow::Result<Self> {
         let _ = SaveToSqlite::new(path.clone())?;
         Ok(ServerConfig {
+            similar: Arc::new(Mutex::new(SqliteSimilarIndex::new(path.clone())?)),
             store_path: Arc::new(path),
+            delta_table: None,
         })
     }
 }
@@ -30,31 +49,464 @@ impl<'r> FromParam<'r> for OrderBy {
     }
 }
 
-#[get("/<order_by>/<limit>")]
+impl<'r> FromParam<'r> for Granularity {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        use std::str::FromStr;
+        Granularity::from_str(param).map_err(|_| param)
+    }
+}
+
+impl<'r> FromParam<'r> for Sort {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        use std::str::FromStr;
+        Sort::from_str(param).map_err(|_| param)
+    }
+}
+
+impl<'v> FromFormField<'v> for MediaType {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        use std::str::FromStr;
+        MediaType::from_str(field.value)
+            .map_err(|_| form::Error::validation("unknown media type").into())
+    }
+}
+
+impl<'v> FromFormField<'v> for Cursor {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        use std::str::FromStr;
+        Cursor::from_str(field.value).map_err(|_| form::Error::validation("bad cursor").into())
+    }
+}
+
+/// Narrows `/list`, times are RFC 3339 and bound the `time_key` time of
+/// records, the first sort key by default. `as_of` is refused, the index
+/// served keeps no history.
+#[derive(FromForm)]
+pub struct ListQuery {
+    media_type: Vec<MediaType>,
+    since: Option<String>,
+    until: Option<String>,
+    time_key: Option<String>,
+    prefix: Option<String>,
+    glob: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    as_of: Option<String>,
+}
+
+impl ListQuery {
+    fn filter(self, sort: &Sort) -> anyhow::Result<Filter> {
+        use std::str::FromStr;
+        if self.as_of.is_some() {
+            anyhow::bail!("the index keeps no history");
+        }
+        let time = |time: Option<String>| -> anyhow::Result<_> {
+            Ok(match time {
+                Some(time) => Some(jiff::Timestamp::from_str(&time)?),
+                None => None,
+            })
+        };
+        let (since, until) = (time(self.since)?, time(self.until)?);
+        let time_range = match (since, until) {
+            (None, None) => None,
+            _ => {
+                let key = match self.time_key {
+                    Some(key) => OrderBy::from_str(&key)?,
+                    None => sort.0[0].order_by,
+                };
+                if key == OrderBy::Path {
+                    anyhow::bail!("path is not a time");
+                }
+                Some(TimeRange { key, since, until })
+            }
+        };
+        Ok(Filter {
+            media_types: self.media_type,
+            time_range,
+            path_prefix: self.prefix.map(PathBuf::from),
+            path_glob: self.glob,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            ..Default::default()
+        })
+    }
+}
+
+/// How a version of the API writes a picture.
+type PictureJson = fn(&BasicPicture) -> serde_json::Value;
+
+/// Pictures as served before `/v1/`, kept for existing clients.
+fn unversioned_json(picture: &BasicPicture) -> serde_json::Value {
+    serde_json::to_value(picture).expect("json")
+}
+
+fn v1_json(picture: &BasicPicture) -> serde_json::Value {
+    serde_json::to_value(v1::Picture::from(picture)).expect("json")
+}
+
+/// A `/list` page with the `after` of the following page in its
+/// `Next-Page` header, which is left out after the last.
+pub struct ListPage<R> {
+    pictures: R,
+    next: Option<String>,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for ListPage<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.pictures.respond_to(request)?;
+        if let Some(next) = self.next {
+            response.set_raw_header("Next-Page", next);
+        }
+        Ok(response)
+    }
+}
+
+/// At most `limit` pictures as a json array, the ones following `after`.
+/// `sort` is like `-ExifCreateTime,Path`, newest first then by path.
+#[get("/<sort>/<limit>?<after>&<query..>")]
 //#[get("/<limit>")]
 pub async fn list(
     server_config: &State<ServerConfig>,
-    order_by: OrderBy,
+    sort: Sort,
+    limit: usize,
+    after: Option<Cursor>,
+    query: ListQuery,
+) -> Result<ListPage<TextStream![String]>, Status> {
+    let (pictures, next) = list_page(
+        server_config,
+        sort,
+        limit,
+        after,
+        query,
+        unversioned_json,
+        false,
+    )?;
+    Ok(ListPage { pictures, next })
+}
+
+/// `list` as one page `{"pictures": [..], "next": ..}`, pictures as
+/// `v1::Picture` and `next` being the `after` of the following page, null
+/// after the last.
+#[get("/<sort>/<limit>?<after>&<query..>")]
+pub async fn list_v1(
+    server_config: &State<ServerConfig>,
+    sort: Sort,
+    limit: usize,
+    after: Option<Cursor>,
+    query: ListQuery,
+) -> Result<TextStream![String], Status> {
+    let (pictures, _) = list_page(server_config, sort, limit, after, query, v1_json, true)?;
+    Ok(pictures)
+}
+
+/// The pictures of a page and the `after` of the page following it, `paged`
+/// also writes that `after` with the pictures.
+fn list_page(
+    server_config: &State<ServerConfig>,
+    sort: Sort,
     limit: usize,
-) -> TextStream![String] {
-    let mut read = SqliteReader::new(server_config.store_path.to_path_buf()).expect("ok");
-    TextStream! {
+    after: Option<Cursor>,
+    query: ListQuery,
+    to_json: PictureJson,
+    paged: bool,
+) -> Result<(TextStream![String], Option<String>), Status> {
+    if after.as_ref().is_some_and(|after| after.sort != sort) {
+        return Err(Status::BadRequest);
+    }
+    let Ok(filter) = query.filter(&sort) else {
+        return Err(Status::BadRequest);
+    };
+    let filter = Filter { after, ..filter };
+    // one more than the page tells whether another follows, as unreadable
+    // records are skipped a short page doesn't
+    let fetch = match limit {
+        0 => 0,
+        limit => limit.saturating_add(1),
+    };
+    let read = SqliteReader::new(server_config.store_path.to_path_buf())
+        .map_err(|_| Status::InternalServerError)?;
+    let mut pictures: Box<dyn Iterator<Item = BasicPicture> + Send> = Box::new(
+        read.query(sort.clone(), filter, fetch)
+            .map_err(|_| Status::InternalServerError)?,
+    );
+
+    // a page is read whole before answering, its next goes first
+    let next = match limit {
+        0 => None,
+        limit => {
+            let page: Vec<BasicPicture> = pictures.by_ref().take(limit).collect();
+            let next = match (page.last(), pictures.next()) {
+                (Some(last), Some(_)) => Some(Cursor::after(sort, last).to_string()),
+                _ => None,
+            };
+            pictures = Box::new(page.into_iter());
+            next
+        }
+    };
+    let end = match paged {
+        true => format!(
+            "\n], \"next\": {}}}\n",
+            serde_json::to_string(&next).expect("json")
+        ),
+        false => "\n]\n".to_owned(),
+    };
+
+    let stream = TextStream! {
+
+        yield match paged {
+            true => "{\"pictures\": [\n".to_owned(),
+            false => "[\n".to_owned(),
+        };
+
+        for (count, v) in pictures.enumerate() {
+            if count != 0 {
+                yield ",\n".to_owned();
+            }
+
+            let j = serde_json::to_string(&to_json(&v)).expect("json");
+            yield j;
+        }
+
+        yield end;
+    };
+    Ok((stream, next))
+}
 
+/// Versions of the delta table, `limit` at most, newest first, each with
+/// the time and operation of the commit which made it.
+#[cfg(feature = "delta")]
+#[get("/?<limit>")]
+pub async fn versions(
+    server_config: &State<ServerConfig>,
+    limit: Option<usize>,
+) -> Result<TextStream![String], Status> {
+    let uri = server_config
+        .delta_table
+        .as_deref()
+        .ok_or(Status::BadRequest)?;
+    let versions =
+        delta::versions(uri, limit.unwrap_or(0)).map_err(|_| Status::InternalServerError)?;
+    Ok(TextStream! {
         yield "[\n".to_owned();
-        let res = read.load(order_by, limit).expect("ok");
 
         let mut first = true;
-        for v in res {
+        for version in versions {
+            if !first {
+                yield ",\n".to_owned();
+            } else {
+                first = false;
+            }
+
+            yield serde_json::to_string(&version).expect("json");
+        }
+        yield "\n]\n".to_owned();
+    })
+}
+
+#[cfg(feature = "delta")]
+#[derive(Serialize)]
+struct ChangeJson {
+    version: i64,
+    change: delta::Change,
+    picture: serde_json::Value,
+}
+
+/// Changes made to the delta table by versions `from` to `to` included, up
+/// to the latest without `to`, oldest first, pictures as `v1::Picture`.
+#[cfg(feature = "delta")]
+#[get("/<from>?<to>")]
+pub async fn changes_v1(
+    server_config: &State<ServerConfig>,
+    from: i64,
+    to: Option<i64>,
+) -> Result<TextStream![String], Status> {
+    let uri = server_config
+        .delta_table
+        .as_deref()
+        .ok_or(Status::BadRequest)?;
+    // such as versions never written or written before the feed was enabled
+    let changes = delta::changes(uri, from, to).map_err(|_| Status::BadRequest)?;
+    Ok(TextStream! {
+        yield "[\n".to_owned();
 
-            if !first{
+        let mut first = true;
+        for change in changes {
+            if !first {
                 yield ",\n".to_owned();
-            }else{
+            } else {
                 first = false;
             }
 
-            let j = serde_json::to_string(&v).expect("json");
-            yield j;
+            yield serde_json::to_string(&ChangeJson {
+                version: change.version,
+                change: change.change,
+                picture: v1_json(&change.picture),
+            })
+            .expect("json");
         }
         yield "\n]\n".to_owned();
+    })
+}
+
+/// JSON Schema of the pictures served under `/v1/`.
+#[get("/picture.json")]
+pub async fn schema_v1() -> (ContentType, &'static str) {
+    (ContentType::JSON, v1::PICTURE_SCHEMA)
+}
+
+/// Number of pictures in each year, month or day of their `key` time,
+/// `[{"start": "2024-07-01", "count": 12}, ..]` oldest first. Takes the
+/// query of `/list`.
+#[get("/<key>/<granularity>?<query..>")]
+pub async fn timeline(
+    server_config: &State<ServerConfig>,
+    key: OrderBy,
+    granularity: Granularity,
+    query: ListQuery,
+) -> Result<TextStream![String], Status> {
+    if key == OrderBy::Path {
+        return Err(Status::BadRequest);
     }
+    let Ok(filter) = query.filter(&key.into()) else {
+        return Err(Status::BadRequest);
+    };
+    let mut read = SqliteReader::new(server_config.store_path.to_path_buf())
+        .map_err(|_| Status::InternalServerError)?;
+    let buckets = read
+        .timeline(key, granularity, filter)
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(TextStream! {
+        yield "[\n".to_owned();
+
+        let mut first = true;
+        for bucket in buckets {
+            if !first {
+                yield ",\n".to_owned();
+            } else {
+                first = false;
+            }
+
+            yield serde_json::to_string(&bucket).expect("json");
+        }
+        yield "\n]\n".to_owned();
+    })
+}
+
+/// Groups of identical files, `limit` groups at most, largest files first.
+#[get("/?<limit>")]
+pub async fn duplicates(
+    server_config: &State<ServerConfig>,
+    limit: Option<usize>,
+) -> Result<TextStream![String], Status> {
+    let mut read = SqliteReader::new(server_config.store_path.to_path_buf())
+        .map_err(|_| Status::InternalServerError)?;
+    let groups = read
+        .duplicates(limit.unwrap_or(0))
+        .map_err(|_| Status::InternalServerError)?;
+    Ok(TextStream! {
+        yield "[\n".to_owned();
+
+        let mut first = true;
+        for group in groups {
+            if !first {
+                yield ",\n".to_owned();
+            } else {
+                first = false;
+            }
+
+            yield serde_json::to_string(&group).expect("json");
+        }
+        yield "\n]\n".to_owned();
+    })
+}
+
+/// Hamming distance under which `/similar` takes two pictures for the same.
+const SIMILAR_DISTANCE: u32 = 10;
+
+#[derive(Serialize)]
+struct SimilarPicture {
+    distance: u32,
+    #[serde(flatten)]
+    picture: serde_json::Value,
+}
+
+/// Pictures looking like picture `id`, resized, recompressed or lightly
+/// edited copies of it, closest first.
+#[get("/<id>?<distance>")]
+pub async fn similar(
+    server_config: &State<ServerConfig>,
+    id: i64,
+    distance: Option<u32>,
+) -> Result<Option<TextStream![String]>, Status> {
+    similar_pictures(server_config, id, distance, unversioned_json).await
+}
+
+/// `similar` with pictures as `v1::Picture`.
+#[get("/<id>?<distance>")]
+pub async fn similar_v1(
+    server_config: &State<ServerConfig>,
+    id: i64,
+    distance: Option<u32>,
+) -> Result<Option<TextStream![String]>, Status> {
+    similar_pictures(server_config, id, distance, v1_json).await
+}
+
+async fn similar_pictures(
+    server_config: &State<ServerConfig>,
+    id: i64,
+    distance: Option<u32>,
+    to_json: PictureJson,
+) -> Result<Option<TextStream![String]>, Status> {
+    // the index reads every record when the store changed, off the runtime
+    let index = server_config.similar.clone();
+    let found = rocket::tokio::task::spawn_blocking(move || {
+        index
+            .lock()
+            .expect("lock")
+            .similar(id, distance.unwrap_or(SIMILAR_DISTANCE))
+    })
+    .await
+    .map_err(|_| Status::InternalServerError)?
+    .map_err(|_| Status::InternalServerError)?;
+    let Some(found) = found else {
+        return Ok(None);
+    };
+
+    let distances: HashMap<i64, u32> = found.iter().copied().collect();
+    let filter = Filter {
+        ids: Some(found.iter().map(|(id, _)| *id).collect()),
+        ..Default::default()
+    };
+    let mut read = SqliteReader::new(server_config.store_path.to_path_buf())
+        .map_err(|_| Status::InternalServerError)?;
+    let mut pictures: Vec<_> = read
+        .load(OrderBy::FsCreateTime.into(), filter, 0)
+        .map_err(|_| Status::InternalServerError)?
+        .map(|picture| (distances[&picture.id], picture))
+        .collect();
+    pictures.sort_by_key(|(distance, picture)| (*distance, picture.id));
+
+    Ok(Some(TextStream! {
+        yield "[\n".to_owned();
+
+        let mut first = true;
+        for (distance, picture) in pictures {
+            if !first {
+                yield ",\n".to_owned();
+            } else {
+                first = false;
+            }
+
+            let similar = SimilarPicture {
+                distance,
+                picture: to_json(&picture),
+            };
+            yield serde_json::to_string(&similar).expect("json");
+        }
+        yield "\n]\n".to_owned();
+    }))
 }
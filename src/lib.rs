@@ -2,15 +2,22 @@ pub mod common;
 #[cfg(feature = "delta")]
 pub mod delta;
 pub mod http;
+pub mod media;
+pub mod metadata;
+pub mod similar;
 #[cfg(feature = "sqlite")]
 pub mod sqlite;
+pub mod v1;
+pub mod watch;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::common::{
-        BasicPicture, FsOpCallback, OrderBy, PictureRecord, Store, StoreReader, walk_files,
+        BasicPicture, FileIdentity, Filter, FsOpCallback, OrderBy, PictureRecord, Store,
+        StoreReader, TimeSource, walk_files,
     };
+    use crate::media::MediaType;
 
     use anyhow::Result;
     use function_name::named;
@@ -26,6 +33,8 @@ mod tests {
     struct RandomPathGenerator {
         rng: rand::rngs::SmallRng,
         current: Vec<(String, u32)>,
+        // names taken below the current parent, as in a real directory
+        siblings: Vec<std::collections::HashSet<String>>,
         depth: u32,
         width: u32,
     }
@@ -54,14 +63,19 @@ mod tests {
             let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
 
             let mut current = vec![];
+            let mut siblings = vec![];
             for _ in 0..depth {
-                current.push((RandomPathGenerator::random_string(&mut rng), 1));
+                let name = RandomPathGenerator::random_string(&mut rng);
+                siblings.push(std::collections::HashSet::from([name.clone()]));
+                current.push((name, 1));
             }
             *current.last_mut().unwrap() = ("".into(), 0);
+            siblings.last_mut().unwrap().clear();
 
             Ok(RandomPathGenerator {
                 rng,
                 current,
+                siblings,
                 depth,
                 width,
             })
@@ -75,7 +89,16 @@ mod tests {
 
             let adv_res = self.current[rightmost_adv].1 + 1;
             for i in rightmost_adv..self.depth as usize {
-                self.current[i] = (RandomPathGenerator::random_string(&mut self.rng), 1);
+                if i > rightmost_adv {
+                    self.siblings[i].clear();
+                }
+                let name = loop {
+                    let name = RandomPathGenerator::random_string(&mut self.rng);
+                    if self.siblings[i].insert(name.clone()) {
+                        break name;
+                    }
+                };
+                self.current[i] = (name, 1);
             }
             self.current[rightmost_adv].1 = adv_res;
 
@@ -97,40 +120,69 @@ mod tests {
         fn flush(&mut self) -> Result<()> {
             Ok(())
         }
+
+        fn on_remove(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_missing(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_move(&mut self, _from: &Path, _to: &Path, _identity: FileIdentity) -> Result<()> {
+            Ok(())
+        }
     }
 
     fn test_no_save() {
         walk_files(Path::new(&get_walk_dir()), &mut ()).expect("walk success");
     }
 
-    //use crate::delta::SaveToDelta;
+    #[cfg(feature = "delta")]
+    use crate::delta::SaveToDelta;
 
     use crate::sqlite::SaveToSqlite;
     use counter::Counter;
 
     struct Checker {
-        result: Counter<String>,
+        result: Counter<PathBuf>,
     }
     impl FsOpCallback for Checker {
         fn on_op(&mut self, picture: PictureRecord) -> Result<()> {
-            self.result[&picture.path.to_owned()] += 1;
+            self.result[&picture.path] += 1;
             Ok(())
         }
 
         fn flush(&mut self) -> Result<()> {
             Ok(())
         }
+
+        fn on_remove(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_missing(&mut self, _path: &Path) -> Result<()> {
+            Ok(())
+        }
+
+        fn on_move(&mut self, _from: &Path, _to: &Path, _identity: FileIdentity) -> Result<()> {
+            Ok(())
+        }
     }
 
     fn test_write_read_compare(store: impl Store) {
         writer_benchmark(&mut store.writer());
         let mut reader = store.reader();
-        let mut checker = Counter::<String>::new();
-        let res = reader.load(OrderBy::FsModifyTime, 0).expect("read ok");
+        let mut checker = Counter::<PathBuf>::new();
+        let res = reader
+            .load(OrderBy::FsModifyTime.into(), Filter::default(), 0)
+            .expect("read ok");
         for v in res {
             checker[&v.path] += 1;
         }
-        let expect = rand_path_generator().into_iter().collect::<Counter<_>>();
+        let expect = rand_path_generator()
+            .map(PathBuf::from)
+            .collect::<Counter<_>>();
 
         assert_eq!(checker, expect);
     }
@@ -149,11 +201,21 @@ mod tests {
         test_write_read_compare(sqlite_store);
     }
 
+    #[test]
+    #[named]
+    fn test_sqlite_write_twice() {
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        writer_benchmark(&mut store.writer());
+        // the second write of a path updates its row
+        test_write_read_compare(store);
+    }
+
     #[test]
     #[named]
     #[cfg(feature = "delta")]
     fn test_write_delta() {
-        let delta = SaveToDelta::new(function_name!().into()).expect("ok");
+        let delta = SaveToDelta::new(function_name!()).expect("ok");
         walk_files(Path::new(&get_walk_dir()), &mut delta.writer()).expect("walk success");
     }
     #[test]
@@ -168,13 +230,31 @@ mod tests {
         RandomPathGenerator::new(3, 80).expect("gen random")
     }
 
+    /// A jpeg at `path` created and modified at `time`, tests set whatever
+    /// else they need with struct update syntax.
+    fn picture_record(
+        path: &str,
+        time: &jiff::Zoned,
+        exif_create_time: Option<&jiff::Zoned>,
+    ) -> PictureRecord {
+        PictureRecord {
+            path: path.into(),
+            media_type: MediaType::Jpeg,
+            size: 0,
+            inode: 0,
+            fs_create_time: common::Zoned(time.clone()),
+            fs_create_time_source: TimeSource::Birth,
+            fs_modify_time: common::Zoned(time.clone()),
+            exif_create_time: exif_create_time.cloned().map(common::Zoned),
+            content_hash: [0; 32],
+            perceptual_hash: None,
+        }
+    }
+
     fn writer_benchmark(callback: &mut dyn FsOpCallback) {
         for path in rand_path_generator() {
             callback
-                .on_op(PictureRecord {
-                    path: path.into(),
-                    fs_create_time: common::Zoned(jiff::Zoned::now()),
-                })
+                .on_op(picture_record(&path, &jiff::Zoned::now(), None))
                 .expect("ok");
         }
         callback.flush().expect("flush ok");
@@ -192,13 +272,1829 @@ mod tests {
     #[named]
     #[cfg(feature = "delta")]
     fn test_deltalake_benchmark() {
-        let delta = SaveToDelta::new(function_name!().into()).expect("ok");
+        let delta = SaveToDelta::new(function_name!()).expect("ok");
         writer_benchmark(&mut delta.writer());
     }
 
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_partitions() {
+        use crate::common::{Granularity, TimeRange, TimelineBucket};
+
+        let _ = std::fs::remove_dir_all(function_name!());
+        let delta = SaveToDelta::new(function_name!()).expect("ok");
+        let mut writer = delta.writer();
+        let summer = jiff::civil::date(2024, 7, 1).in_tz("UTC").expect("zoned");
+        for (i, name) in ["/a.jpg", "/b.jpg", "/c.jpg"].iter().enumerate() {
+            let months = jiff::Span::new().months(i as i64 * 6);
+            let time = summer.checked_add(months).expect("add");
+            writer
+                .on_op(picture_record(name, &summer, Some(&time)))
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        for partition in [
+            "capture_year=2024/capture_month=7",
+            "capture_year=2025/capture_month=1",
+            "capture_year=2025/capture_month=7",
+        ] {
+            assert!(Path::new(function_name!()).join(partition).is_dir());
+        }
+
+        let january = Filter {
+            time_range: Some(TimeRange {
+                key: OrderBy::ExifCreateTime,
+                since: Some(
+                    jiff::civil::date(2025, 1, 1)
+                        .in_tz("UTC")
+                        .expect("zoned")
+                        .timestamp(),
+                ),
+                until: Some(
+                    jiff::civil::date(2025, 2, 1)
+                        .in_tz("UTC")
+                        .expect("zoned")
+                        .timestamp(),
+                ),
+            }),
+            ..Default::default()
+        };
+        // other months are never read, not even when their files are gone
+        for partition in [
+            "capture_year=2024/capture_month=7",
+            "capture_year=2025/capture_month=7",
+        ] {
+            std::fs::remove_dir_all(Path::new(function_name!()).join(partition)).expect("remove");
+        }
+        let buckets = delta
+            .reader()
+            .timeline(OrderBy::ExifCreateTime, Granularity::Month, january)
+            .expect("timeline ok");
+        assert_eq!(
+            buckets,
+            [TimelineBucket {
+                start: jiff::civil::date(2025, 1, 1),
+                count: 1
+            }]
+        );
+        assert!(
+            delta
+                .reader()
+                .load(OrderBy::ExifCreateTime.into(), Filter::default(), 0)
+                .map(|pictures| pictures.count())
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_partition_migration() {
+        use deltalake::datafusion::prelude::SessionContext;
+
+        let current = format!("{}_current", function_name!());
+        let _ = std::fs::remove_dir_all(&current);
+        let _ = std::fs::remove_dir_all(function_name!());
+        let delta = SaveToDelta::new(&current).expect("ok");
+        let mut writer = delta.writer();
+        let summer = jiff::civil::date(2024, 7, 1).in_tz("UTC").expect("zoned");
+        let winter = jiff::civil::date(2025, 1, 1).in_tz("UTC").expect("zoned");
+        writer
+            .on_op(picture_record("/a.jpg", &summer, None))
+            .expect("ok");
+        writer
+            .on_op(picture_record("/b.jpg", &summer, Some(&winter)))
+            .expect("ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        // the same rows as a table from before the partitions
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime
+            .block_on(async {
+                let ctx = SessionContext::new();
+                ctx.register_table(
+                    "current",
+                    std::sync::Arc::new(deltalake::open_table(&current).await?),
+                )?;
+                let batches = ctx
+                    .sql("select * except (capture_year, capture_month) from current")
+                    .await?
+                    .collect()
+                    .await?;
+                deltalake::DeltaOps::try_from_uri(function_name!())
+                    .await?
+                    .write(batches)
+                    .await
+            })
+            .expect("legacy table");
+
+        let load = |delta: &SaveToDelta| {
+            delta
+                .reader()
+                .load(OrderBy::Path.into(), Filter::default(), 0)
+                .expect("read ok")
+                .map(|v| (v.path, v.id, v.exif_create_time.map(|t| t.0.timestamp())))
+                .collect::<Vec<_>>()
+        };
+        let migrated = SaveToDelta::new(function_name!()).expect("migrate ok");
+        assert_eq!(load(&migrated), load(&delta));
+        for partition in [
+            "capture_year=2024/capture_month=7",
+            "capture_year=2025/capture_month=1",
+        ] {
+            assert!(Path::new(function_name!()).join(partition).is_dir());
+        }
+        // rewritten by a single commit, then the change feed enabled
+        let versions = crate::delta::versions(function_name!(), 0).expect("versions ok");
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.version, v.operation.as_deref()))
+                .collect::<Vec<_>>()[1..],
+            [(1, Some("WRITE")), (0, Some("WRITE"))]
+        );
+        let _ = std::fs::remove_dir_all(&current);
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_changes() {
+        use crate::delta::Change;
+
+        let _ = std::fs::remove_dir_all(function_name!());
+        let delta = SaveToDelta::new(function_name!()).expect("ok");
+        let mut writer = delta.writer();
+        let record = |name: &str| picture_record(name, &jiff::Zoned::now(), None);
+        writer.on_op(record("/a.jpg")).expect("ok");
+        writer.on_op(record("/b.jpg")).expect("ok");
+        writer.flush().expect("flush ok");
+        writer.on_op(record("/a.jpg")).expect("ok");
+        writer.flush().expect("flush ok");
+        writer.on_missing(Path::new("/a.jpg")).expect("missing ok");
+        writer.on_remove(Path::new("/b.jpg")).expect("remove ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        let changes = |from, to| {
+            crate::delta::changes(function_name!(), from, to)
+                .expect("changes ok")
+                .into_iter()
+                .map(|event| {
+                    let path = event.picture.path.to_string_lossy().into_owned();
+                    (event.version, event.change, path)
+                })
+                .collect::<Counter<_>>()
+        };
+        let all = changes(1, None);
+        assert_eq!(
+            all,
+            [
+                (1, Change::Insert, "/a.jpg".to_owned()),
+                (1, Change::Insert, "/b.jpg".to_owned()),
+                (2, Change::Update, "/a.jpg".to_owned()),
+                (3, Change::Missing, "/a.jpg".to_owned()),
+                (4, Change::Delete, "/b.jpg".to_owned()),
+            ]
+            .into_iter()
+            .collect::<Counter<_>>()
+        );
+        assert_eq!(changes(3, Some(3)).len(), 1);
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_changes_enabled_later() {
+        use crate::delta::Change;
+        use deltalake::datafusion::prelude::SessionContext;
+
+        let with_feed = format!("{}_with_feed", function_name!());
+        let _ = std::fs::remove_dir_all(&with_feed);
+        let _ = std::fs::remove_dir_all(function_name!());
+        let delta = SaveToDelta::new(&with_feed).expect("ok");
+        let mut writer = delta.writer();
+        let now = jiff::Zoned::now();
+        writer
+            .on_op(picture_record("/a.jpg", &now, None))
+            .expect("ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        // the same rows in a table created without the feed
+        let runtime = deltalake::storage::IORuntime::default().get_handle();
+        runtime
+            .block_on(async {
+                let ctx = SessionContext::new();
+                ctx.register_table(
+                    "with_feed",
+                    std::sync::Arc::new(deltalake::open_table(&with_feed).await?),
+                )?;
+                let batches = ctx.sql("select * from with_feed").await?.collect().await?;
+                deltalake::DeltaOps::try_from_uri(function_name!())
+                    .await?
+                    .write(batches)
+                    .with_partition_columns(["capture_year", "capture_month"])
+                    .await
+            })
+            .expect("table without feed");
+
+        let delta = SaveToDelta::new(function_name!()).expect("ok");
+        let mut writer = delta.writer();
+        writer
+            .on_op(picture_record("/b.jpg", &now, None))
+            .expect("ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        // version 1 turned the feed on
+        let changes: Vec<_> = crate::delta::changes(function_name!(), 2, None)
+            .expect("changes ok")
+            .into_iter()
+            .map(|event| (event.version, event.change, event.picture.path))
+            .collect();
+        assert_eq!(changes, [(2, Change::Insert, PathBuf::from("/b.jpg"))]);
+        let _ = std::fs::remove_dir_all(&with_feed);
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_time_travel() {
+        use crate::common::AsOf;
+
+        let _ = std::fs::remove_dir_all(function_name!());
+        let mut delta = SaveToDelta::new(function_name!()).expect("ok");
+        let mut writer = delta.writer();
+        let now = jiff::Zoned::now();
+        for name in ["/photos/a.jpg", "/photos/b.jpg"] {
+            writer.on_op(picture_record(name, &now, None)).expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        writer
+            .on_remove(Path::new("/photos/a.jpg"))
+            .expect("remove ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        let versions = crate::delta::versions(function_name!(), 0).expect("versions ok");
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (v.version, v.operation.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                (2, Some("DELETE")),
+                (1, Some("MERGE")),
+                (0, Some("CREATE TABLE"))
+            ]
+        );
+
+        let load_names = |delta: &SaveToDelta, as_of| {
+            delta
+                .reader()
+                .load(
+                    OrderBy::Path.into(),
+                    Filter {
+                        as_of,
+                        ..Default::default()
+                    },
+                    0,
+                )
+                .expect("read ok")
+                .map(|picture| picture.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(load_names(&delta, None), [PathBuf::from("/photos/b.jpg")]);
+        assert_eq!(
+            load_names(&delta, Some(AsOf::Version(1))),
+            [
+                PathBuf::from("/photos/a.jpg"),
+                PathBuf::from("/photos/b.jpg")
+            ]
+        );
+        assert!(load_names(&delta, Some(AsOf::Version(0))).is_empty());
+
+        delta.restore(AsOf::Version(1)).expect("restore ok");
+        assert_eq!(load_names(&delta, None).len(), 2);
+        let latest = crate::delta::versions(function_name!(), 2).expect("versions ok");
+        assert_eq!(
+            latest
+                .iter()
+                .map(|v| (v.version, v.operation.as_deref()))
+                .collect::<Vec<_>>(),
+            [(3, Some("RESTORE")), (2, Some("DELETE"))]
+        );
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_maintain() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        let mut delta = SaveToDelta::new(function_name!()).expect("ok");
+        // a small file per flush for compaction to merge
+        let mut writer = delta.writer();
+        let now = jiff::Zoned::now();
+        for batch in 0..5 {
+            for i in 0..10 {
+                writer
+                    .on_op(picture_record(&format!("/{batch}/{i}.jpg"), &now, None))
+                    .expect("ok");
+            }
+            writer.flush().expect("flush ok");
+        }
+        drop(writer);
+        let count = |delta: &SaveToDelta| {
+            delta
+                .reader()
+                .load(OrderBy::Path.into(), Filter::default(), 0)
+                .expect("read ok")
+                .count()
+        };
+        let before = count(&delta);
+
+        let report = delta
+            .maintain(&crate::delta::MaintenanceOptions {
+                retention_hours: 0,
+                ..Default::default()
+            })
+            .expect("maintain ok");
+        assert!(report.files_added < report.files_removed);
+        // merges leave replaced files behind too
+        assert!(report.files_vacuumed as u64 >= report.files_removed);
+        assert_eq!(before, 50);
+        assert_eq!(count(&delta), before);
+    }
+
+    /// A TIFF file holding only the given ASCII EXIF fields.
+    fn write_exif_tiff(path: &Path, fields: &[(exif::Tag, &str)]) {
+        use exif::{Field, In, Value};
+
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|&(tag, value)| Field {
+                tag,
+                ifd_num: In::PRIMARY,
+                value: Value::Ascii(vec![value.into()]),
+            })
+            .collect();
+
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields.iter() {
+            writer.push_field(field);
+        }
+        let mut tiff = std::io::Cursor::new(vec![]);
+        writer.write(&mut tiff, true).expect("write exif");
+        std::fs::write(path, tiff.into_inner()).expect("write file");
+    }
+
+    #[test]
+    #[named]
+    fn test_exif_create_time() {
+        use exif::Tag;
+
+        let path = PathBuf::from(format!("{}.tif", function_name!()));
+        write_exif_tiff(
+            &path,
+            &[
+                (Tag::DateTimeOriginal, "2021:07:04 10:20:30"),
+                (Tag::SubSecTimeOriginal, "25"),
+                (Tag::OffsetTimeOriginal, "+02:00"),
+            ],
+        );
+
+        let exif_create_time = metadata::exif_create_time(&path)
+            .expect("read ok")
+            .expect("has exif");
+        assert_eq!(
+            exif_create_time.timestamp(),
+            "2021-07-04T08:20:30.25Z"
+                .parse::<jiff::Timestamp>()
+                .unwrap()
+        );
+        assert_eq!(exif_create_time.offset(), jiff::tz::offset(2));
+        assert_eq!(
+            exif_create_time.datetime(),
+            jiff::civil::datetime(2021, 7, 4, 10, 20, 30, 250_000_000)
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_exif_create_time_placeholder() {
+        let path = PathBuf::from(format!("{}.tif", function_name!()));
+        write_exif_tiff(
+            &path,
+            &[(exif::Tag::DateTimeOriginal, "0000:00:00 00:00:00")],
+        );
+        assert_eq!(metadata::exif_create_time(&path).expect("read ok"), None);
+    }
+
+    #[test]
+    #[named]
+    fn test_exif_create_time_unreadable() {
+        // reading a directory fails with an io error other than an early eof
+        let _ = std::fs::create_dir(function_name!());
+        assert_eq!(
+            metadata::exif_create_time(Path::new(function_name!())).expect("read ok"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_detect_media_type() {
+        use crate::media::detect_bytes;
+
+        let cases: [(&[u8], &str, Option<MediaType>); 9] = [
+            (b"\xFF\xD8\xFF\xE1\0\0Exif", "jpg", Some(MediaType::Jpeg)),
+            (b"\x89PNG\r\n\x1a\n", "png", Some(MediaType::Png)),
+            (b"II*\0\x08\0\0\0", "tif", Some(MediaType::Tiff)),
+            (b"II*\0\x08\0\0\0", "nef", Some(MediaType::Raw)),
+            (
+                b"\0\0\0\x18ftypheic\0\0\0\0mif1heic",
+                "heic",
+                Some(MediaType::Heic),
+            ),
+            (
+                b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf",
+                "avif",
+                Some(MediaType::Avif),
+            ),
+            (
+                b"\0\0\0\x14ftypqt  \0\0\0\0qt  ",
+                "mov",
+                Some(MediaType::Mov),
+            ),
+            (b"\0\0\0\x05\x16\x07\0\x02", "txt", None),
+            (b"", "jpeg", Some(MediaType::Jpeg)),
+        ];
+        for (head, extension, expect) in cases {
+            assert_eq!(detect_bytes(head, extension), expect, "{extension}");
+        }
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_media_type_filter() {
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        for (name, media_type) in [
+            ("/a.jpg", MediaType::Jpeg),
+            ("/b.png", MediaType::Png),
+            ("/c.mov", MediaType::Mov),
+            ("/d.mp4", MediaType::Mp4),
+        ] {
+            writer
+                .on_op(PictureRecord {
+                    media_type,
+                    ..picture_record(name, &now, None)
+                })
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+
+        let filter = Filter {
+            media_types: vec![MediaType::Mov, MediaType::Mp4],
+            ..Default::default()
+        };
+        let res: Vec<_> = store
+            .reader()
+            .load(OrderBy::Path.into(), filter, 0)
+            .expect("read ok")
+            .map(|v| (v.path, v.media_type))
+            .collect();
+        assert_eq!(
+            res,
+            [
+                (PathBuf::from("/c.mov"), MediaType::Mov),
+                (PathBuf::from("/d.mp4"), MediaType::Mp4),
+            ]
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_rescan() {
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).expect("create dir");
+        for name in ["a.jpg", "b.jpg", "sub/c.jpg"] {
+            std::fs::write(root.join(name), b"\xFF\xD8\xFF\xE0").expect("write");
+        }
+        std::fs::write(root.join("notes.txt"), b"not a picture").expect("write");
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+
+        let stats = walk_files(&root, &mut store.writer()).expect("walk success");
+        assert_eq!((stats.inserted, stats.updated, stats.unchanged), (3, 0, 0));
+
+        let stats = walk_files(&root, &mut store.writer()).expect("walk success");
+        assert_eq!((stats.inserted, stats.updated, stats.unchanged), (0, 0, 3));
+
+        std::fs::write(root.join("b.jpg"), b"\xFF\xD8\xFF\xE0\0\0").expect("write");
+        let stats = walk_files(&root, &mut store.writer()).expect("walk success");
+        assert_eq!((stats.inserted, stats.updated, stats.unchanged), (0, 1, 2));
+
+        let count = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .count();
+        assert_eq!(count, 3);
+
+        // a stopped walk leaves records of files it didn't get to alone
+        use crate::common::walk_files_until;
+        std::fs::remove_file(root.join("a.jpg")).expect("remove");
+        let stop = std::sync::atomic::AtomicBool::new(true);
+        let stats = walk_files_until(&root, &mut store.writer(), &Default::default(), &stop)
+            .expect("walk success");
+        assert_eq!((stats.inserted, stats.unchanged, stats.missing), (0, 0, 0));
+        let count = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .count();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_order_by_modify_time() {
+        let _ = std::fs::remove_file(function_name!());
+        test_order_by_modify_time(
+            SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create"),
+        );
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_order_by_modify_time() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        test_order_by_modify_time(SaveToDelta::new(function_name!()).expect("ok"));
+    }
+
+    fn test_order_by_modify_time(store: impl Store) {
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        // created in name order, modified in reverse
+        for (i, name) in ["/a.jpg", "/b.jpg", "/c.jpg"].iter().enumerate() {
+            let hours = jiff::Span::new().hours(i as i64);
+            writer
+                .on_op(PictureRecord {
+                    fs_modify_time: common::Zoned(now.checked_sub(hours).expect("sub")),
+                    ..picture_record(name, &now.checked_add(hours).expect("add"), None)
+                })
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+
+        let load_paths = |order_by: OrderBy| {
+            store
+                .reader()
+                .load(order_by.into(), Filter::default(), 0)
+                .expect("read ok")
+                .map(|v| v.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            load_paths(OrderBy::FsCreateTime),
+            ["/a.jpg", "/b.jpg", "/c.jpg"].map(PathBuf::from)
+        );
+        assert_eq!(
+            load_paths(OrderBy::FsModifyTime),
+            ["/c.jpg", "/b.jpg", "/a.jpg"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_filter() {
+        let _ = std::fs::remove_file(function_name!());
+        test_filter(SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create"));
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_filter() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        test_filter(SaveToDelta::new(function_name!()).expect("ok"));
+    }
+
+    fn test_filter(store: impl Store) {
+        use crate::common::TimeRange;
+
+        let mut writer = store.writer();
+        let summer = jiff::civil::date(2024, 7, 1).in_tz("UTC").expect("zoned");
+        for (i, name) in [
+            "/photos/2024/a.jpg",
+            "/photos/2024/b.png",
+            "/photos/2024-trip/c.jpg",
+            "/photos/2025/d.jpg",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let months = jiff::Span::new().months(i as i64 * 3);
+            let time = summer.checked_add(months).expect("add");
+            // created in name order, ids break ties differently per store
+            let created = summer
+                .checked_add(jiff::Span::new().seconds(i as i64))
+                .expect("add");
+            writer
+                .on_op(PictureRecord {
+                    media_type: match name.ends_with(".png") {
+                        true => MediaType::Png,
+                        false => MediaType::Jpeg,
+                    },
+                    size: 1000 * i as u64,
+                    ..picture_record(name, &created, (i != 1).then_some(&time))
+                })
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        let load_names = |filter| {
+            store
+                .reader()
+                .load(OrderBy::FsCreateTime.into(), filter, 0)
+                .expect("read ok")
+                .map(|v| v.path.file_name().expect("name").to_owned())
+                .collect::<Vec<_>>()
+        };
+        let end_of_summer = jiff::Timestamp::from_second(1727740800).expect("timestamp");
+        assert_eq!(
+            load_names(Filter {
+                time_range: Some(TimeRange {
+                    key: OrderBy::ExifCreateTime,
+                    since: Some(summer.timestamp()),
+                    until: Some(end_of_summer),
+                }),
+                ..Default::default()
+            }),
+            ["a.jpg"]
+        );
+        assert_eq!(
+            load_names(Filter {
+                time_range: Some(TimeRange {
+                    key: OrderBy::ExifCreateTime,
+                    since: Some(end_of_summer),
+                    until: None,
+                }),
+                ..Default::default()
+            }),
+            ["c.jpg", "d.jpg"]
+        );
+        assert_eq!(
+            load_names(Filter {
+                path_prefix: Some("/photos/2024".into()),
+                ..Default::default()
+            }),
+            ["a.jpg", "b.png"]
+        );
+        assert_eq!(
+            load_names(Filter {
+                path_glob: Some("/photos/*/?.jpg".into()),
+                ..Default::default()
+            }),
+            ["a.jpg", "c.jpg", "d.jpg"]
+        );
+        // classes as sqlite's glob has them, on every store
+        assert_eq!(
+            load_names(Filter {
+                path_glob: Some("/photos/202[45]/*".into()),
+                ..Default::default()
+            }),
+            ["a.jpg", "b.png", "d.jpg"]
+        );
+        assert_eq!(
+            load_names(Filter {
+                path_glob: Some("/photos/202[^5]/*.[jp][pn]g".into()),
+                ..Default::default()
+            }),
+            ["a.jpg", "b.png"]
+        );
+        assert_eq!(
+            load_names(Filter {
+                path_glob: Some("/photos/2024[*".into()),
+                ..Default::default()
+            }),
+            Vec::<std::ffi::OsString>::new()
+        );
+        assert_eq!(
+            load_names(Filter {
+                media_types: vec![MediaType::Jpeg],
+                min_size: Some(1000),
+                max_size: Some(2000),
+                ..Default::default()
+            }),
+            ["c.jpg"]
+        );
+
+        // latin-1, a byte sqlite's glob reads as one character
+        let latin1 = crate::common::path_from_bytes(b"/latin1/caf\xE9.jpg".to_vec());
+        let mut writer = store.writer();
+        writer
+            .on_op(PictureRecord {
+                path: latin1.clone(),
+                ..picture_record("", &summer, None)
+            })
+            .expect("ok");
+        writer.flush().expect("flush ok");
+        drop(writer);
+        for filter in [
+            Filter {
+                path_prefix: Some("/latin1".into()),
+                ..Default::default()
+            },
+            Filter {
+                path_glob: Some("/latin1/caf?.jpg".into()),
+                ..Default::default()
+            },
+        ] {
+            assert_eq!(load_names(filter), [latin1.file_name().expect("name")]);
+        }
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_timeline() {
+        let _ = std::fs::remove_file(function_name!());
+        test_timeline(SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create"));
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_timeline() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        test_timeline(SaveToDelta::new(function_name!()).expect("ok"));
+    }
+
+    fn test_timeline(store: impl Store) {
+        use crate::common::{Granularity, TimelineBucket};
+        use jiff::civil::date;
+
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        // new year in Tokyo is still the old one in UTC, and the other way
+        // round in New York
+        for (i, exif_create_time) in [
+            Some(date(2024, 1, 1).at(2, 0, 0, 0).in_tz("Asia/Tokyo")),
+            Some(date(2024, 1, 1).at(9, 0, 0, 0).in_tz("Asia/Tokyo")),
+            Some(
+                date(2023, 12, 31)
+                    .at(23, 30, 0, 0)
+                    .in_tz("America/New_York"),
+            ),
+            Some(date(1969, 12, 31).at(23, 59, 30, 0).in_tz("UTC")),
+            None,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            writer
+                .on_op(picture_record(
+                    &format!("/{i}.jpg"),
+                    &now,
+                    exif_create_time.map(|zoned| zoned.expect("zoned")).as_ref(),
+                ))
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+
+        let timeline = |granularity| {
+            store
+                .reader()
+                .timeline(OrderBy::ExifCreateTime, granularity, Filter::default())
+                .expect("timeline")
+        };
+        assert_eq!(
+            timeline(Granularity::Year),
+            [
+                TimelineBucket {
+                    start: date(1969, 1, 1),
+                    count: 1
+                },
+                TimelineBucket {
+                    start: date(2023, 1, 1),
+                    count: 1
+                },
+                TimelineBucket {
+                    start: date(2024, 1, 1),
+                    count: 2
+                },
+            ]
+        );
+        assert_eq!(
+            timeline(Granularity::Day)
+                .iter()
+                .map(|bucket| bucket.start)
+                .collect::<Vec<_>>(),
+            [date(1969, 12, 31), date(2023, 12, 31), date(2024, 1, 1)]
+        );
+        assert_eq!(
+            store
+                .reader()
+                .timeline(OrderBy::FsCreateTime, Granularity::Month, Filter::default())
+                .expect("timeline")
+                .iter()
+                .map(|bucket| bucket.count)
+                .sum::<u64>(),
+            5
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_time_zones() {
+        use jiff::tz::{Offset, TimeZone};
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        let zones = [
+            TimeZone::get("Asia/Tokyo").expect("tz"),
+            // what EXIF OffsetTime gives
+            TimeZone::fixed(Offset::from_seconds(5 * 3600 + 30 * 60).expect("offset")),
+            TimeZone::posix("EST5EDT,M3.2.0,M11.1.0").expect("tz"),
+        ];
+        let times: Vec<jiff::Zoned> = zones
+            .iter()
+            .enumerate()
+            .map(|(i, zone)| {
+                jiff::civil::date(2024, 7, 1 + i as i8)
+                    .at(12, 0, 0, 0)
+                    .to_zoned(zone.clone())
+                    .expect("zoned")
+            })
+            .collect();
+        for (i, time) in times.iter().enumerate() {
+            writer
+                .on_op(PictureRecord {
+                    fs_modify_time: common::Zoned(now.clone()),
+                    ..picture_record(&format!("/{i}.jpg"), time, Some(time))
+                })
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+
+        let loaded: Vec<BasicPicture> = store
+            .reader()
+            .load(OrderBy::ExifCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .collect();
+        assert_eq!(loaded.len(), times.len());
+        for (picture, time) in loaded.iter().zip(&times) {
+            assert_eq!(&picture.fs_create_time.0, time);
+            assert_eq!(picture.exif_create_time.as_ref().map(|t| &t.0), Some(time));
+        }
+        let json = serde_json::to_string(&loaded[1]).expect("json");
+        assert!(json.contains(r#""time_zone":"+05:30""#), "{json}");
+    }
+
+    #[test]
+    fn test_v1_picture_schema() {
+        let schema: serde_json::Value =
+            serde_json::from_str(v1::PICTURE_SCHEMA).expect("schema json");
+        let tokyo = jiff::civil::date(2024, 7, 1)
+            .at(12, 0, 0, 0)
+            .in_tz("Asia/Tokyo")
+            .expect("zoned");
+        let fixed = tokyo.with_time_zone(jiff::tz::TimeZone::fixed(jiff::tz::offset(-3)));
+        let rule = jiff::tz::TimeZone::posix("EST5EDT,M3.2.0,M11.1.0").expect("posix");
+        let eastern = tokyo.with_time_zone(rule);
+        let picture = BasicPicture {
+            id: 7,
+            path: "/a.jpg".into(),
+            media_type: MediaType::Jpeg,
+            fs_create_time: common::Zoned(tokyo.clone()),
+            fs_create_time_source: TimeSource::Modify,
+            fs_modify_time: common::Zoned(eastern),
+            exif_create_time: Some(common::Zoned(fixed)),
+        };
+        let json = serde_json::to_value(v1::Picture::from(&picture)).expect("json");
+
+        let keys = |value: &serde_json::Value| -> Vec<String> {
+            let mut keys: Vec<String> =
+                value.as_object().expect("object").keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys(&json), keys(&schema["properties"]));
+        let mut required: Vec<&str> = schema["required"]
+            .as_array()
+            .expect("required")
+            .iter()
+            .map(|key| key.as_str().expect("key"))
+            .collect();
+        required.sort();
+        assert_eq!(keys(&json), required);
+
+        let time_keys = keys(&schema["$defs"]["time"]["properties"]);
+        assert_eq!(keys(&json["fs_create_time"]), time_keys);
+        assert_eq!(
+            json["fs_create_time"],
+            serde_json::json!({
+                "time": "2024-07-01T12:00:00+09:00",
+                "time_zone": "Asia/Tokyo",
+                "time_zone_rule": null,
+            })
+        );
+        assert_eq!(
+            json["fs_modify_time"],
+            serde_json::json!({
+                "time": "2024-06-30T23:00:00-04:00",
+                "time_zone": null,
+                "time_zone_rule": "EST5EDT,M3.2.0,M11.1.0",
+            })
+        );
+        assert_eq!(
+            json["exif_create_time"],
+            serde_json::json!({
+                "time": "2024-07-01T00:00:00-03:00",
+                "time_zone": null,
+                "time_zone_rule": null,
+            })
+        );
+        assert_eq!(json["fs_create_time_source"], "Modify");
+    }
+
+    #[test]
+    #[named]
+    fn test_http_list_pages() {
+        use rocket::local::blocking::Client;
+
+        let _ = std::fs::remove_file(function_name!());
+        let config = http::ServerConfig::new(PathBuf::from(function_name!())).expect("config");
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let mut writer = store.writer();
+        for name in ["/a.jpg", "/b.jpg", "/c.jpg", "/d.jpg"] {
+            writer
+                .on_op(picture_record(name, &jiff::Zoned::now(), None))
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        drop(writer);
+
+        let rocket = rocket::build()
+            .manage(config)
+            .mount("/list", rocket::routes![http::list])
+            .mount("/v1/list", rocket::routes![http::list_v1]);
+        let client = Client::tracked(rocket).expect("client");
+        let get = |uri: &str| -> serde_json::Value {
+            let body = client.get(uri).dispatch().into_string().expect("body");
+            serde_json::from_str(&body).expect("json")
+        };
+
+        // the unversioned listing stays a plain array, its next in a header
+        let response = client.get("/list/Path/3").dispatch();
+        let next = response
+            .headers()
+            .get_one("Next-Page")
+            .expect("next")
+            .to_owned();
+        let list: serde_json::Value =
+            serde_json::from_str(&response.into_string().expect("body")).expect("json");
+        assert_eq!(list.as_array().map(Vec::len), Some(3));
+        let uri = format!(
+            "/list/Path/3?after={}",
+            rocket::http::RawStr::new(&next).percent_encode()
+        );
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.headers().get_one("Next-Page"), None);
+        let list: serde_json::Value =
+            serde_json::from_str(&response.into_string().expect("body")).expect("json");
+        assert_eq!(list.as_array().map(Vec::len), Some(1));
+
+        let list = get(&format!("/list/Path/{}", usize::MAX));
+        assert_eq!(list.as_array().map(Vec::len), Some(4));
+
+        let page = get("/v1/list/Path/2");
+        assert_eq!(page["pictures"].as_array().map(Vec::len), Some(2));
+        let schema: serde_json::Value =
+            serde_json::from_str(v1::PICTURE_SCHEMA).expect("schema json");
+        let keys = |value: &serde_json::Value| -> Vec<String> {
+            let mut keys: Vec<String> =
+                value.as_object().expect("object").keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+        assert_eq!(
+            keys(&page),
+            keys(&schema["$defs"]["list_page"]["properties"])
+        );
+        let next = page["next"].as_str().expect("next");
+        let uri = format!(
+            "/v1/list/Path/2?after={}",
+            rocket::http::RawStr::new(next).percent_encode()
+        );
+        let page = get(&uri);
+        assert_eq!(page["pictures"].as_array().map(Vec::len), Some(2));
+        // a full last page has no next
+        assert_eq!(page["next"], serde_json::Value::Null);
+
+        // times bound by path, the first sort key, or asked for
+        for uri in [
+            "/list/Path/2?since=2024-01-01T00:00:00Z",
+            "/list/-FsCreateTime/2?since=2024-01-01T00:00:00Z&time_key=Path",
+        ] {
+            let response = client.get(uri).dispatch();
+            assert_eq!(response.status(), rocket::http::Status::BadRequest);
+        }
+
+        // the index served keeps no history
+        let response = client.get("/list/Path/2?as_of=1").dispatch();
+        assert_eq!(response.status(), rocket::http::Status::BadRequest);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_cursor_pages() {
+        let _ = std::fs::remove_file(function_name!());
+        test_cursor_pages(
+            SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create"),
+        );
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_cursor_pages() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        test_cursor_pages(SaveToDelta::new(function_name!()).expect("ok"));
+    }
+
+    fn test_cursor_pages(store: impl Store) {
+        use crate::common::{Cursor, Sort};
+        use std::str::FromStr;
+
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        // ties in every key, and a key missing for some
+        for i in 0..20 {
+            let hours = jiff::Span::new().hours(i / 3);
+            let time = now.checked_add(hours).expect("add");
+            writer
+                .on_op(PictureRecord {
+                    fs_modify_time: common::Zoned(now.clone()),
+                    ..picture_record(&format!("/{i}.jpg"), &time, (i % 2 == 0).then_some(&time))
+                })
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+
+        for sort in [
+            "FsCreateTime",
+            "FsModifyTime",
+            "ExifCreateTime",
+            "-FsCreateTime",
+            "-ExifCreateTime",
+            "-ExifCreateTime,Path",
+            "FsModifyTime,-Path",
+        ] {
+            let sort = Sort::from_str(sort).expect("sort");
+            let all: Vec<BasicPicture> = store
+                .reader()
+                .load(sort.clone(), Filter::default(), 0)
+                .expect("read ok")
+                .collect();
+            assert_eq!(all.len(), 20);
+
+            let mut paged = vec![];
+            let mut after = None;
+            loop {
+                let filter = Filter {
+                    after: after.take(),
+                    ..Default::default()
+                };
+                let page: Vec<BasicPicture> = store
+                    .reader()
+                    .load(sort.clone(), filter, 3)
+                    .expect("read ok")
+                    .collect();
+                let Some(last) = page.last() else {
+                    break;
+                };
+                // the token is what clients hand back
+                let cursor = Cursor::after(sort.clone(), last);
+                after = Some(Cursor::from_str(&cursor.to_string()).expect("parse"));
+                assert_eq!(after.as_ref(), Some(&cursor));
+                paged.extend(page.iter().map(|v| v.id));
+            }
+            assert_eq!(paged, all.iter().map(|v| v.id).collect::<Vec<_>>());
+        }
+
+        // newest first, those without a capture time last
+        let newest: Vec<PathBuf> = store
+            .reader()
+            .load(
+                Sort::from_str("-ExifCreateTime,Path").expect("sort"),
+                Filter::default(),
+                0,
+            )
+            .expect("read ok")
+            .map(|v| v.path)
+            .collect();
+        assert_eq!(
+            newest[..4],
+            ["/18.jpg", "/16.jpg", "/12.jpg", "/14.jpg"].map(PathBuf::from)
+        );
+        assert_eq!(
+            newest[10..13],
+            ["/1.jpg", "/11.jpg", "/13.jpg"].map(PathBuf::from)
+        );
+
+        let cursor = Cursor::after(OrderBy::FsCreateTime.into(), &{
+            store
+                .reader()
+                .load(OrderBy::FsCreateTime.into(), Filter::default(), 1)
+                .expect("read ok")
+                .next()
+                .expect("one")
+        });
+        let filter = Filter {
+            after: Some(cursor),
+            ..Default::default()
+        };
+        assert!(
+            store
+                .reader()
+                .load(OrderBy::FsModifyTime.into(), filter, 0)
+                .is_err()
+        );
+        assert!(Cursor::from_str("FsCreateTime.1").is_err());
+
+        // rewriting a record keeps its id
+        let load_ids = || {
+            store
+                .reader()
+                .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+                .expect("read ok")
+                .map(|v| (v.path, v.id))
+                .collect::<std::collections::HashMap<_, _>>()
+        };
+        let before = load_ids();
+        writer
+            .on_op(PictureRecord {
+                size: 1,
+                ..picture_record("/0.jpg", &now, None)
+            })
+            .expect("ok");
+        writer.flush().expect("flush ok");
+        assert_eq!(load_ids(), before);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_create_time_source() {
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).expect("create dir");
+        std::fs::write(root.join("a.jpg"), b"\xFF\xD8\xFF").expect("write");
+        let metadata = std::fs::metadata(root.join("a.jpg")).expect("stat");
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        walk_files(&root, &mut store.writer()).expect("walk success");
+
+        let pictures: Vec<_> = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .collect();
+        assert_eq!(pictures.len(), 1);
+        let (expect_time, expect_source) = match metadata.created() {
+            Ok(created) => (created, TimeSource::Birth),
+            Err(_) => (metadata.modified().expect("mtime"), TimeSource::Modify),
+        };
+        assert_eq!(pictures[0].fs_create_time_source, expect_source);
+        // stored to the microsecond
+        let expect_time = jiff::Timestamp::try_from(expect_time).expect("timestamp");
+        assert_eq!(
+            pictures[0].fs_create_time.0.timestamp(),
+            crate::common::whole_micros(expect_time)
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_parallel_walk() {
+        use crate::common::{WalkOptions, walk_files_with};
+
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in 0..8 {
+            std::fs::create_dir_all(root.join(format!("{dir}/.hidden"))).expect("create dir");
+            std::fs::write(root.join(format!("{dir}/.hidden/x.jpg")), b"\xFF\xD8\xFF")
+                .expect("write");
+            for file in 0..50 {
+                std::fs::write(root.join(format!("{dir}/{file}.jpg")), b"\xFF\xD8\xFF")
+                    .expect("write");
+            }
+        }
+
+        for workers in [1, 4] {
+            let db = format!("{}_{workers}", function_name!());
+            let _ = std::fs::remove_file(&db);
+            let store = SaveToSqlite::new(PathBuf::from(db)).expect("sqlite create");
+            let options = WalkOptions {
+                workers,
+                ..Default::default()
+            };
+
+            let stats = walk_files_with(&root, &mut store.writer(), &options).expect("walk");
+            assert_eq!((stats.inserted, stats.unchanged), (400, 0));
+            let stats = walk_files_with(&root, &mut store.writer(), &options).expect("walk");
+            assert_eq!((stats.inserted, stats.unchanged), (0, 400));
+        }
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_reconcile() {
+        let _ = std::fs::remove_file(function_name!());
+        test_reconcile(
+            SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create"),
+            &PathBuf::from(format!("{}_dir", function_name!())),
+        );
+    }
+
+    #[test]
+    #[named]
+    #[cfg(feature = "delta")]
+    fn test_deltalake_reconcile() {
+        let _ = std::fs::remove_dir_all(function_name!());
+        test_reconcile(
+            SaveToDelta::new(function_name!()).expect("ok"),
+            &PathBuf::from(format!("{}_dir", function_name!())),
+        );
+    }
+
+    fn test_reconcile(store: impl Store, root: &Path) {
+        use crate::common::{MissingPolicy, WalkOptions, walk_files_with};
+
+        let _ = std::fs::remove_dir_all(root);
+        std::fs::create_dir_all(root.join("moved")).expect("create dir");
+        for (name, len) in [("a.jpg", 1), ("b.jpg", 2), ("c.jpg", 3)] {
+            let content = [b"\xFF\xD8\xFF".as_slice(), &vec![0; len]].concat();
+            std::fs::write(root.join(name), content).expect("write");
+        }
+
+        let stats = walk_files(root, &mut store.writer()).expect("walk success");
+        assert_eq!(stats.inserted, 3);
+
+        std::fs::rename(root.join("a.jpg"), root.join("moved/a.jpg")).expect("rename");
+        std::fs::remove_file(root.join("b.jpg")).expect("remove");
+        let stats = walk_files(root, &mut store.writer()).expect("walk success");
+        assert_eq!((stats.inserted, stats.moved, stats.missing), (0, 1, 1));
+
+        let load_paths = || {
+            let mut paths: Vec<_> = store
+                .reader()
+                .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+                .expect("read ok")
+                .map(|v| v.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+        let expect: Vec<_> = ["c.jpg", "moved/a.jpg"].map(|name| root.join(name)).into();
+        assert_eq!(load_paths(), expect);
+
+        std::fs::remove_file(root.join("c.jpg")).expect("remove");
+        let options = WalkOptions {
+            missing: MissingPolicy::Remove,
+            ..Default::default()
+        };
+        let stats = walk_files_with(root, &mut store.writer(), &options).expect("walk success");
+        assert_eq!(stats.missing, 1);
+        assert_eq!(load_paths(), expect[1..]);
+
+        // same size and modification time, other content: not a move
+        let modified = std::fs::metadata(root.join("moved/a.jpg"))
+            .and_then(|metadata| metadata.modified())
+            .expect("mtime");
+        std::fs::remove_file(root.join("moved/a.jpg")).expect("remove");
+        std::fs::write(root.join("d.jpg"), b"\xFF\xD8\xFF\x01").expect("write");
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("d.jpg"))
+            .and_then(|file| file.set_modified(modified))
+            .expect("set mtime");
+        let stats = walk_files(root, &mut store.writer()).expect("walk success");
+        assert_eq!((stats.inserted, stats.moved, stats.missing), (1, 0, 1));
+        assert_eq!(load_paths(), [root.join("d.jpg")]);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_scan_report() {
+        use crate::common::{ScanErrorKind, WalkOptions, walk_files_with};
+        use crate::sqlite::SqliteReader;
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("locked")).expect("create dir");
+        for name in ["a.jpg", "locked/b.jpg"] {
+            std::fs::write(root.join(name), b"\xFF\xD8\xFF").expect("write");
+        }
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let options = WalkOptions {
+            persist_report: true,
+            ..Default::default()
+        };
+        let report = walk_files_with(&root, &mut store.writer(), &options).expect("walk");
+        assert_eq!(report.inserted, 2);
+        assert_eq!(report.errors, []);
+
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o000))
+            .expect("chmod");
+        // permissions don't apply to root, which reads the locked directory
+        let is_root = std::os::unix::fs::MetadataExt::uid(&root.metadata().expect("stat")) == 0;
+        assert_eq!(std::fs::read_dir(root.join("locked")).is_ok(), is_root);
+        let report = walk_files_with(&root, &mut store.writer(), &options).expect("walk");
+        if is_root {
+            assert_eq!((report.unchanged, report.missing), (2, 0));
+            assert_eq!(report.errors, []);
+        } else {
+            assert_eq!((report.unchanged, report.missing), (1, 0));
+            assert!(
+                report
+                    .errors
+                    .iter()
+                    .any(|e| e.kind == ScanErrorKind::PermissionDenied)
+            );
+        }
+        std::fs::set_permissions(root.join("locked"), std::fs::Permissions::from_mode(0o755))
+            .expect("chmod");
+
+        // an unmounted library is not a deleted one
+        let unmounted = PathBuf::from(format!("{}_unmounted", function_name!()));
+        let _ = std::fs::remove_dir_all(&unmounted);
+        std::fs::rename(&root, &unmounted).expect("rename");
+        let report = walk_files_with(&root, &mut store.writer(), &options).expect("walk");
+        assert_eq!(report.missing, 0);
+        assert_eq!(report.errors.len(), 1);
+        std::fs::rename(&unmounted, &root).expect("rename");
+
+        let reports = SqliteReader::new(PathBuf::from(function_name!()))
+            .expect("open")
+            .scan_reports(&root)
+            .expect("reports");
+        assert_eq!(reports.first(), Some(&report));
+        assert!(reports.len() >= 2);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_flush_failure() {
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+
+        // another writer holds the database longer than a statement waits
+        let locker = rusqlite::Connection::open(function_name!()).expect("open");
+        locker.execute_batch("begin immediate").expect("lock");
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(crate::sqlite::BUSY_TIMEOUT * 2);
+            locker.execute_batch("commit").expect("unlock");
+        });
+
+        let mut writer = store.writer();
+        let now = jiff::Zoned::now();
+        for i in 0..50 {
+            let record = picture_record(&format!("/{i}.jpg"), &now, None);
+            // beyond what a sqlite integer holds
+            let size = if i == 0 { u64::MAX } else { 0 };
+            writer.on_op(PictureRecord { size, ..record }).expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        release.join().expect("join");
+
+        let dropped = writer.take_dropped();
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].0, Path::new("/0.jpg"));
+        let count = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .count();
+        assert_eq!(count, 49);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_ancient_times() {
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let mut writer = store.writer();
+        // the zero FILETIME archives and Samba leave, and the far future
+        let times = [
+            jiff::civil::date(1601, 1, 1).in_tz("UTC").expect("zoned"),
+            jiff::civil::date(9999, 1, 1).in_tz("UTC").expect("zoned"),
+        ];
+        for (i, time) in times.iter().enumerate() {
+            writer
+                .on_op(picture_record(&format!("/{i}.jpg"), time, None))
+                .expect("ok");
+        }
+        writer.flush().expect("flush ok");
+        assert!(writer.take_dropped().is_empty());
+
+        let stored: Vec<_> = store
+            .reader()
+            .load(OrderBy::FsModifyTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .map(|picture| picture.fs_modify_time.0)
+            .collect();
+        assert_eq!(stored, times);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_final_flush_failure() {
+        use crate::common::ScanErrorKind;
+
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).expect("create dir");
+        for i in 0..3 {
+            std::fs::write(root.join(format!("{i}.jpg")), b"\xFF\xD8\xFF").expect("write");
+        }
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+
+        // the database stays locked past the only flush, the one ending the walk
+        let locker = rusqlite::Connection::open(function_name!()).expect("open");
+        locker.execute_batch("begin immediate").expect("lock");
+        let report = walk_files(&root, &mut store.writer()).expect("walk");
+        locker.execute_batch("commit").expect("unlock");
+
+        assert_eq!(report.errors.len(), 3);
+        assert!(
+            report
+                .errors
+                .iter()
+                .all(|error| error.kind == ScanErrorKind::Store)
+        );
+        let count = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .count();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        // Latin-1 names, as left behind by old Samba shares
+        let dir = root.join(std::ffi::OsStr::from_bytes(b"\xc9t\xe9"));
+        let latin1 = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.jpg"));
+        let utf8 = root.join("café.jpg");
+        std::fs::create_dir_all(&dir).expect("create dir");
+        for path in [&latin1, &utf8] {
+            std::fs::write(path, b"\xFF\xD8\xFF").expect("write");
+        }
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        let report = walk_files(&root, &mut store.writer()).expect("walk");
+        assert_eq!((report.inserted, report.errors.len()), (2, 0));
+
+        let pictures: Vec<_> = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .collect();
+        let mut paths: Vec<_> = pictures.iter().map(|p| p.path.clone()).collect();
+        paths.sort();
+        assert_eq!(paths, [utf8.clone(), latin1.clone()]);
+
+        let json: Vec<_> = pictures
+            .iter()
+            .map(|p| serde_json::to_value(p).expect("json")["path"].clone())
+            .collect();
+        let expect = format!("{}/\\xC9t\\xE9/caf\\xE9.jpg", root.display());
+        assert!(
+            json.contains(&serde_json::Value::String(expect)),
+            "{json:?}"
+        );
+        // a name that only looks escaped stays apart from the Latin-1 one
+        assert_eq!(
+            crate::common::escaped_path(Path::new("caf\\xE9.jpg")),
+            "caf\\\\xE9.jpg"
+        );
+
+        let report = walk_files(&root, &mut store.writer()).expect("walk");
+        assert_eq!(report.unchanged, 2);
+
+        std::fs::remove_file(&latin1).expect("remove");
+        let report = walk_files(&root, &mut store.writer()).expect("walk");
+        assert_eq!(report.missing, 1);
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_duplicates() {
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("backup")).expect("create dir");
+        let small = b"\xFF\xD8\xFF".as_slice();
+        let large = b"\xFF\xD8\xFF\xE0\0\0".as_slice();
+        for (name, content) in [
+            ("a.jpg", small),
+            ("backup/a.jpg", small),
+            ("b.jpg", large),
+            ("backup/b.jpg", large),
+            ("backup/old_b.jpg", large),
+            ("c.jpg", b"\xFF\xD8\xFF\0".as_slice()),
+        ] {
+            std::fs::write(root.join(name), content).expect("write");
+        }
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        walk_files(&root, &mut store.writer()).expect("walk success");
+        std::fs::remove_file(root.join("backup/old_b.jpg")).expect("remove");
+        walk_files(&root, &mut store.writer()).expect("walk success");
+
+        let groups = store.reader().duplicates(0).expect("duplicates");
+        let groups: Vec<_> = groups.into_iter().map(|g| (g.size, g.paths)).collect();
+        let paths = |names: [&str; 2]| names.map(|name| root.join(name)).to_vec();
+        assert_eq!(
+            groups,
+            [
+                (6, paths(["b.jpg", "backup/b.jpg"])),
+                (3, paths(["a.jpg", "backup/a.jpg"]))
+            ]
+        );
+
+        let groups = store.reader().duplicates(1).expect("duplicates");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].content_hash,
+            blake3::hash(large).to_hex().to_string()
+        );
+    }
+
+    #[test]
+    fn test_bk_tree() {
+        use crate::similar::{BkTree, distance};
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+        let hashes: Vec<u64> = (0..2000).map(|_| rng.next_u64()).collect();
+        let mut tree = BkTree::default();
+        for (i, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, i);
+        }
+        assert_eq!(tree.len(), hashes.len());
+
+        for query in hashes.iter().take(20) {
+            // flip a few bits so there is something near but not equal
+            let query = query ^ 0b1011;
+            let mut found: Vec<_> = tree.find(query, 24).into_iter().map(|(_, i)| *i).collect();
+            found.sort();
+            let expect: Vec<_> = (0..hashes.len())
+                .filter(|i| distance(hashes[*i], query) <= 24)
+                .collect();
+            assert_eq!(found, expect);
+        }
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_similar() {
+        use crate::sqlite::SqliteSimilarIndex;
+
+        let root = PathBuf::from(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).expect("create dir");
+
+        let picture = image::RgbImage::from_fn(320, 240, |x, y| {
+            let v = ((x / 40 + y / 30) % 2 * 160 + x / 4) as u8;
+            image::Rgb([v, v / 2, 255 - v])
+        });
+        picture.save(root.join("original.png")).expect("save");
+        // a smaller, recompressed copy
+        image::DynamicImage::ImageRgb8(picture.clone())
+            .resize(160, 120, image::imageops::FilterType::Triangle)
+            .save(root.join("copy.jpg"))
+            .expect("save");
+        image::imageops::flip_horizontal(&picture)
+            .save(root.join("other.png"))
+            .expect("save");
+
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+        walk_files(&root, &mut store.writer()).expect("walk success");
+
+        let ids: std::collections::HashMap<_, _> = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .map(|v| (v.path.file_name().unwrap().to_owned(), v.id))
+            .collect();
+        let id = |name: &str| ids[std::ffi::OsStr::new(name)];
+
+        let mut index = SqliteSimilarIndex::new(PathBuf::from(function_name!())).expect("open");
+        let found = index.similar(id("original.png"), 10).expect("similar");
+        let found: Vec<_> = found
+            .expect("hashed")
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(found, [id("copy.jpg")]);
+
+        assert_eq!(index.similar(-1, 10).expect("similar"), None);
+
+        // more ids than sqlite takes variables, as a wide distance finds
+        let filter = Filter {
+            ids: Some((0..100_000).chain([id("copy.jpg")]).collect()),
+            ..Default::default()
+        };
+        let count = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), filter, 0)
+            .expect("read ok")
+            .count();
+        assert_eq!(count, ids.len());
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_migrate_baseline() {
+        let _ = std::fs::remove_file(function_name!());
+        {
+            // as written by the first release, which appended on every walk
+            let conn = rusqlite::Connection::open(function_name!()).expect("open");
+            conn.execute_batch(
+                "create table if not exists records(path,fs_create_time_timestamp, fs_create_time_timezone);
+                insert into records values ('/a.jpg', '2024-05-01T09:00:00Z', 'UTC');
+                insert into records values ('/notes.txt', '2024-05-01T09:00:00Z', 'UTC');
+                insert into records values ('/b.PNG', '2024-05-01T10:00:00Z', 'UTC');
+                insert into records values ('/a.jpg', '2024-05-02T09:00:00Z', 'Europe/Paris');
+                insert into records values ('/c.jpg', 'not a time', 'UTC');",
+            )
+            .expect("baseline schema");
+        }
+
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("migrate");
+        let pictures: Vec<_> = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .collect();
+        let paths: Vec<_> = pictures.iter().map(|p| p.path.clone()).collect();
+        assert_eq!(paths, ["/b.PNG", "/a.jpg"].map(PathBuf::from));
+        assert_eq!(pictures[0].media_type, MediaType::Png);
+        // the newest row of a path is kept
+        assert_eq!(
+            pictures[1].fs_create_time.0.timestamp(),
+            "2024-05-02T09:00:00Z".parse().unwrap()
+        );
+        assert_eq!(
+            pictures[1].fs_create_time.0.time_zone().iana_name(),
+            Some("Europe/Paris")
+        );
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_migrate_legacy() {
+        let _ = std::fs::remove_file(function_name!());
+        {
+            // as created before the schema was versioned
+            let conn = rusqlite::Connection::open(function_name!()).expect("open");
+            conn.execute_batch(
+                "create table records(path, media_type, size, inode, fs_create_time_timestamp, fs_create_time_timezone, fs_modify_time_timestamp, fs_modify_time_timezone, exif_create_time_timestamp, exif_create_time_timezone, missing);
+                create unique index records_path on records(path);
+                insert into records values ('/b.jpg', 'Jpeg', 1, 2, '2024-05-01T10:00:00.5Z', 'UTC', '2024-05-01T10:00:00Z', 'UTC', null, null, 0);
+                insert into records values ('/a.jpg', 'Jpeg', 1, 3, '2024-05-01T09:00:00Z', 'UTC', '2024-05-01T09:00:00Z', 'UTC', '2020-01-01T00:00:00Z', 'UTC', 0);",
+            )
+            .expect("legacy schema");
+        }
+
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("migrate");
+        let pictures: Vec<_> = store
+            .reader()
+            .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+            .expect("read ok")
+            .collect();
+        let paths: Vec<_> = pictures.iter().map(|p| p.path.clone()).collect();
+        assert_eq!(paths, ["/a.jpg", "/b.jpg"].map(PathBuf::from));
+        assert_eq!(
+            pictures[1].fs_create_time.0.timestamp(),
+            "2024-05-01T10:00:00.5Z".parse().unwrap()
+        );
+        assert_eq!(pictures[1].fs_create_time_source, TimeSource::Birth);
+        assert!(pictures[0].exif_create_time.is_some());
+
+        // reopening is a no-op
+        drop(store);
+        SaveToSqlite::new(PathBuf::from(function_name!())).expect("reopen");
+
+        let conn = rusqlite::Connection::open(function_name!()).expect("open");
+        conn.pragma_update(None, "user_version", 1000)
+            .expect("set version");
+        assert!(SaveToSqlite::new(PathBuf::from(function_name!())).is_err());
+    }
+
+    #[test]
+    #[named]
+    fn test_sqlite_watch() {
+        use crate::watch::{WatchOptions, watch_files};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::time::{Duration, Instant};
+
+        let root = std::fs::canonicalize(".")
+            .expect("cwd")
+            .join(format!("{}_dir", function_name!()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).expect("create dir");
+        let _ = std::fs::remove_file(function_name!());
+        let store = SaveToSqlite::new(PathBuf::from(function_name!())).expect("sqlite create");
+
+        let options = WatchOptions {
+            debounce: Duration::from_millis(100),
+            settle: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let stop = AtomicBool::new(false);
+
+        let wait_for = |expect: Vec<PathBuf>| {
+            let start = Instant::now();
+            loop {
+                let paths: Vec<_> = store
+                    .reader()
+                    .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+                    .expect("read ok")
+                    .map(|v| v.path)
+                    .collect();
+                if paths == expect {
+                    return;
+                }
+                assert!(start.elapsed() < Duration::from_secs(10), "{paths:?}");
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        };
+
+        let roots = [root.clone()];
+        let mut writer = store.writer();
+
+        std::thread::scope(|s| {
+            let (roots, options, stop) = (&roots, &options, &stop);
+            s.spawn(move || watch_files(roots, &mut writer, options, stop).expect("watch ok"));
+
+            let path = root.join("a.jpg");
+            std::thread::sleep(Duration::from_millis(200));
+            std::fs::write(&path, b"\xFF\xD8\xFF\xE0").expect("write");
+            wait_for(vec![path.clone()]);
+
+            let moved = root.join("b.jpg");
+            std::fs::rename(&path, &moved).expect("rename");
+            wait_for(vec![moved.clone()]);
+
+            std::fs::remove_file(&moved).expect("remove");
+            wait_for(vec![]);
+
+            // a directory moved in from outside the root, its files settle too
+            let outside = root.with_extension("outside");
+            let _ = std::fs::remove_dir_all(&outside);
+            std::fs::create_dir_all(&outside).expect("create dir");
+            std::fs::write(outside.join("c.jpg"), b"\xFF\xD8\xFF\xE0").expect("write");
+            std::fs::rename(&outside, root.join("sub")).expect("rename");
+            wait_for(vec![root.join("sub/c.jpg")]);
+
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+
     fn do_read_10(save: &mut impl StoreReader) {
         for _ in 0..10 {
-            let it = save.load(OrderBy::FsCreateTime, 0).expect("read ok");
+            let it = save
+                .load(OrderBy::FsCreateTime.into(), Filter::default(), 0)
+                .expect("read ok");
             for _ in it {}
         }
     }
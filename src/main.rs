@@ -1,4 +1,9 @@
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket};
 
 #[macro_use]
 extern crate rocket;
@@ -6,13 +11,175 @@ extern crate rocket;
 pub mod common;
 pub mod delta;
 pub mod http;
+pub mod media;
+pub mod metadata;
+pub mod similar;
 pub mod sqlite;
+pub mod v1;
+pub mod watch;
+
+use common::Store;
+use sqlite::SaveToSqlite;
+
+/// Keeps the index in sync with the `library_roots` configured for rocket
+/// (`ROCKET_LIBRARY_ROOTS='["/photos"]'` or Rocket.toml) while serving.
+fn watch_library() -> AdHoc {
+    AdHoc::on_liftoff("watch library roots", |rocket| {
+        Box::pin(async move {
+            let roots: Vec<PathBuf> = rocket
+                .figment()
+                .extract_inner("library_roots")
+                .unwrap_or_default();
+            if roots.is_empty() {
+                return;
+            }
+            let store_path = rocket
+                .state::<http::ServerConfig>()
+                .expect("managed")
+                .store_path
+                .to_path_buf();
+
+            let stop = Arc::new(AtomicBool::new(false));
+            let shutdown = rocket.shutdown();
+            let stop_on_shutdown = stop.clone();
+            rocket::tokio::spawn(async move {
+                shutdown.await;
+                stop_on_shutdown.store(true, Ordering::Relaxed);
+            });
+
+            let watch = rocket::tokio::task::spawn_blocking(move || {
+                let store = SaveToSqlite::new(store_path)?;
+                watch::watch_files(
+                    &roots,
+                    &mut store.writer(),
+                    &watch::WatchOptions {
+                        walk: common::WalkOptions {
+                            persist_report: true,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                    &stop,
+                )
+            });
+            rocket::tokio::spawn(async move {
+                match watch.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => error!("watching the library failed: {e:#}"),
+                    Err(e) => error!("watching the library panicked: {e}"),
+                }
+            });
+        })
+    })
+}
+
+/// How often the server maintains the delta table, and how.
+#[cfg(feature = "delta")]
+#[derive(serde::Deserialize)]
+#[serde(default)]
+struct DeltaMaintenance {
+    interval_hours: u64,
+    #[serde(flatten)]
+    options: delta::MaintenanceOptions,
+}
+
+#[cfg(feature = "delta")]
+impl Default for DeltaMaintenance {
+    fn default() -> Self {
+        DeltaMaintenance {
+            interval_hours: 24,
+            options: Default::default(),
+        }
+    }
+}
+
+/// Compacts and vacuums the delta table at `delta_table`, if configured,
+/// every `delta_maintenance.interval_hours` until shutdown
+/// (`ROCKET_DELTA_MAINTENANCE='{interval_hours=24,retention_hours=168}'`).
+/// A `delta_maintenance` which does not parse stops the launch.
+#[cfg(feature = "delta")]
+fn maintain_delta() -> AdHoc {
+    AdHoc::try_on_ignite("maintain delta table", |rocket| async move {
+        let Ok(uri) = rocket.figment().extract_inner::<String>("delta_table") else {
+            return Ok(rocket);
+        };
+        let maintenance: DeltaMaintenance =
+            match rocket.figment().extract_inner("delta_maintenance") {
+                Ok(maintenance) => maintenance,
+                Err(e) if e.missing() => Default::default(),
+                Err(e) => {
+                    error!("invalid delta_maintenance: {e}");
+                    return Err(rocket);
+                }
+            };
+        Ok(
+            rocket.attach(AdHoc::on_liftoff("maintain delta table", move |rocket| {
+                let mut shutdown = rocket.shutdown();
+                Box::pin(async move {
+                    let period =
+                        std::time::Duration::from_secs(maintenance.interval_hours.max(1) * 3600);
+                    let options = Arc::new(maintenance.options);
+                    rocket::tokio::spawn(async move {
+                        let mut interval = rocket::tokio::time::interval(period);
+                        // the first tick is immediate, leave startup alone
+                        interval.tick().await;
+                        loop {
+                            rocket::tokio::select! {
+                                _ = &mut shutdown => return,
+                                _ = interval.tick() => {}
+                            }
+                            let (uri, options) = (uri.clone(), options.clone());
+                            let done = rocket::tokio::task::spawn_blocking(move || {
+                                delta::SaveToDelta::new(&uri)?.maintain(&options)
+                            })
+                            .await;
+                            match done {
+                                Ok(Ok(_)) => {}
+                                Ok(Err(e)) => error!("delta maintenance failed: {e:#}"),
+                                Err(e) => error!("delta maintenance panicked: {e}"),
+                            }
+                        }
+                    });
+                })
+            })),
+        )
+    })
+}
+
+/// Routes reading the delta table.
+#[cfg(feature = "delta")]
+fn mount_delta(rocket: Rocket<Build>) -> Rocket<Build> {
+    rocket
+        .mount("/versions", routes![http::versions])
+        .mount("/v1/changes", routes![http::changes_v1])
+}
+
+#[cfg(not(feature = "delta"))]
+fn mount_delta(rocket: Rocket<Build>) -> Rocket<Build> {
+    rocket
+}
+
+#[cfg(not(feature = "delta"))]
+fn maintain_delta() -> AdHoc {
+    AdHoc::on_liftoff("maintain delta table", |_| Box::pin(async {}))
+}
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-        .manage(http::ServerConfig::new(PathBuf::from("main.sqlite")).expect("db create ok"))
+    let mut config = http::ServerConfig::new(PathBuf::from("main.sqlite")).expect("db create ok");
+    config.delta_table = rocket::Config::figment().extract_inner("delta_table").ok();
+    let rocket = rocket::build()
+        .manage(config)
         .mount("/list", routes![http::list])
+        .mount("/duplicates", routes![http::duplicates])
+        .mount("/similar", routes![http::similar])
+        .mount("/timeline", routes![http::timeline])
+        .mount("/v1/list", routes![http::list_v1])
+        .mount("/v1/similar", routes![http::similar_v1])
+        .mount("/v1/schema", routes![http::schema_v1])
+        .attach(watch_library())
+        .attach(maintain_delta());
+    mount_delta(rocket)
 }
 
 //#[macro_use]
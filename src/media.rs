@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+use strum_macros::{AsRefStr, EnumString};
+
+#[derive(EnumString, AsRefStr, Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MediaType {
+    Jpeg,
+    Png,
+    Heic,
+    Avif,
+    Webp,
+    Gif,
+    Tiff,
+    Raw,
+    Mp4,
+    Mov,
+}
+
+impl MediaType {
+    pub fn is_video(&self) -> bool {
+        matches!(self, MediaType::Mp4 | MediaType::Mov)
+    }
+
+    /// Whether `metadata::perceptual_hash` can decode it.
+    pub fn is_decodable(&self) -> bool {
+        matches!(
+            self,
+            MediaType::Jpeg | MediaType::Png | MediaType::Gif | MediaType::Webp | MediaType::Tiff
+        )
+    }
+}
+
+/// Number of leading bytes needed by `detect_bytes`.
+const SNIFF_LEN: usize = 32;
+
+/// RAW formats that are plain TIFF files, only told apart by extension.
+const TIFF_RAW_EXTENSIONS: [&str; 10] = [
+    "cr2", "nef", "nrw", "arw", "sr2", "dng", "pef", "srw", "3fr", "erf",
+];
+
+/// Directories which only hold caches of other files, never originals.
+const CACHE_DIRS: [&str; 4] = ["@eaDir", ".@__thumb", ".thumbnails", "Thumbs"];
+
+/// Whether a walked entry should be skipped altogether, hidden files such as
+/// `.DS_Store` or AppleDouble `._*` sidecars and thumbnail cache directories.
+pub fn is_ignored(name: &std::ffi::OsStr, is_dir: bool) -> bool {
+    let Some(name) = name.to_str() else {
+        return false;
+    };
+
+    name.starts_with('.') || (is_dir && CACHE_DIRS.contains(&name))
+}
+
+/// Like `is_ignored`, for a path below `root` which may no longer exist.
+pub fn is_ignored_path(path: &Path, root: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        let is_dir = components.peek().is_some();
+        if is_ignored(component.as_os_str(), is_dir) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Detect the media type of a file from its signature, falling back to its
+/// extension when the signature is not recognized. `None` for non media.
+pub fn detect(path: &Path) -> Result<Option<MediaType>> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)?;
+
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+
+    Ok(detect_bytes(&head, &extension))
+}
+
+pub(crate) fn detect_bytes(head: &[u8], extension: &str) -> Option<MediaType> {
+    sniff(head, extension).or_else(|| from_extension(extension))
+}
+
+fn sniff(head: &[u8], extension: &str) -> Option<MediaType> {
+    if head.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some(MediaType::Jpeg);
+    }
+    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(MediaType::Png);
+    }
+    if head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a") {
+        return Some(MediaType::Gif);
+    }
+    if head.starts_with(b"RIFF") && head.get(8..12) == Some(b"WEBP") {
+        return Some(MediaType::Webp);
+    }
+    if head.starts_with(b"II*\0") || head.starts_with(b"MM\0*") {
+        return Some(match TIFF_RAW_EXTENSIONS.contains(&extension) {
+            true => MediaType::Raw,
+            false => MediaType::Tiff,
+        });
+    }
+    // Olympus ORF, Panasonic RW2 and Fujifilm RAF
+    if [b"IIRO", b"IIRS", b"MMOR", b"IIU\0"]
+        .iter()
+        .any(|m| head.starts_with(*m))
+        || head.starts_with(b"FUJIFILMCCD-RAW")
+    {
+        return Some(MediaType::Raw);
+    }
+    if head.get(4..8) == Some(b"ftyp") {
+        return sniff_ftyp(head);
+    }
+    None
+}
+
+/// ISO base media files (HEIF, AVIF, MP4, QuickTime, CR3) share one
+/// container, the `ftyp` brands tell them apart.
+fn sniff_ftyp(head: &[u8]) -> Option<MediaType> {
+    let box_len = u32::from_be_bytes(head[0..4].try_into().ok()?) as usize;
+    let brands = head
+        .get(8..box_len.min(head.len()))?
+        .chunks_exact(4)
+        // minor version is not a brand
+        .enumerate()
+        .filter(|(i, _)| *i != 1)
+        .map(|(_, b)| b);
+
+    let mut found = None;
+    for brand in brands {
+        let media_type = match brand {
+            b"avif" | b"avis" => MediaType::Avif,
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" => MediaType::Heic,
+            b"mif1" | b"msf1" => MediaType::Heic,
+            b"crx " => MediaType::Raw,
+            b"qt  " => MediaType::Mov,
+            b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1"
+            | b"M4V " | b"3gp4" | b"3gp5" | b"3gp6" | b"3g2a" => MediaType::Mp4,
+            _ => continue,
+        };
+        // AVIF also lists the generic mif1 brand, the specific brand wins
+        if found.is_none() || found == Some(MediaType::Heic) && media_type == MediaType::Avif {
+            found = Some(media_type);
+        }
+    }
+    found
+}
+
+fn from_extension(extension: &str) -> Option<MediaType> {
+    Some(match extension {
+        "jpg" | "jpeg" | "jpe" => MediaType::Jpeg,
+        "png" => MediaType::Png,
+        "heic" | "heif" => MediaType::Heic,
+        "avif" => MediaType::Avif,
+        "webp" => MediaType::Webp,
+        "gif" => MediaType::Gif,
+        "tif" | "tiff" => MediaType::Tiff,
+        "cr2" | "cr3" | "nef" | "nrw" | "arw" | "sr2" | "dng" | "orf" | "rw2" | "raf" | "pef"
+        | "srw" | "3fr" | "erf" | "x3f" | "mrw" => MediaType::Raw,
+        "mp4" | "m4v" | "3gp" => MediaType::Mp4,
+        "mov" | "qt" => MediaType::Mov,
+        _ => return None,
+    })
+}
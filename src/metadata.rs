@@ -0,0 +1,137 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+use exif::{In, Reader, Tag};
+
+/// Capture time recorded by the camera, from EXIF DateTimeOriginal together
+/// with OffsetTimeOriginal and SubSecTimeOriginal when present.
+///
+/// DateTimeOriginal is wall clock time of the camera, when no offset is
+/// recorded it is interpreted in the local time zone of the indexer. A
+/// recorded offset is kept as a fixed zone, so the camera's wall clock time
+/// reads back as it was.
+pub fn exif_create_time(path: &Path) -> Result<Option<jiff::Zoned>> {
+    // an unreadable EXIF block only loses the capture time, not the file
+    let Ok(exif) = read_exif(path) else {
+        return Ok(None);
+    };
+
+    let Some(datetime) = ascii_field(&exif, Tag::DateTimeOriginal) else {
+        return Ok(None);
+    };
+    let Ok(mut datetime) = exif::DateTime::from_ascii(datetime) else {
+        return Ok(None);
+    };
+
+    if let Some(subsec) = ascii_field(&exif, Tag::SubSecTimeOriginal) {
+        let _ = datetime.parse_subsec(subsec);
+    }
+    if let Some(offset) = ascii_field(&exif, Tag::OffsetTimeOriginal) {
+        let _ = datetime.parse_offset(offset);
+    }
+
+    // placeholders like 0000:00:00 00:00:00 parse, but are no valid time
+    Ok(to_zoned(&datetime).ok())
+}
+
+/// BLAKE3 hash of the whole file, equal for exact copies.
+pub fn content_hash(path: &Path) -> Result<[u8; 32]> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Rows of the grayscale thumbnail `perceptual_hash` compares, each one
+/// pixel wider than the bits it gives.
+const DHASH_SIZE: u32 = 8;
+
+/// Difference hash of the picture, which stays within a few bits of the
+/// original for resized, recompressed or lightly edited copies. `None` when
+/// it can't be decoded.
+pub fn perceptual_hash(path: &Path) -> Option<u64> {
+    let image = image::ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .decode()
+        .ok()?;
+    let thumbnail = image
+        .thumbnail_exact(DHASH_SIZE + 1, DHASH_SIZE)
+        .into_luma8();
+
+    let mut hash = 0;
+    for y in 0..DHASH_SIZE {
+        for x in 0..DHASH_SIZE {
+            let brighter = thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    Some(hash)
+}
+
+fn ascii_field(exif: &exif::Exif, tag: Tag) -> Option<&[u8]> {
+    match exif.get_field(tag, In::PRIMARY)?.value {
+        exif::Value::Ascii(ref values) => values.first().map(|v| v.as_slice()),
+        _ => None,
+    }
+}
+
+fn to_zoned(datetime: &exif::DateTime) -> Result<jiff::Zoned> {
+    let civil = jiff::civil::DateTime::new(
+        datetime.year.try_into()?,
+        datetime.month.try_into()?,
+        datetime.day.try_into()?,
+        datetime.hour.try_into()?,
+        datetime.minute.try_into()?,
+        datetime.second.try_into()?,
+        datetime.nanosecond.unwrap_or(0).try_into()?,
+    )?;
+
+    let time_zone = match datetime.offset {
+        Some(minutes) => {
+            jiff::tz::TimeZone::fixed(jiff::tz::Offset::from_seconds(i32::from(minutes) * 60)?)
+        }
+        None => jiff::tz::TimeZone::system(),
+    };
+
+    Ok(civil.to_zoned(time_zone)?)
+}
+
+/// TIFF variants used by some RAW formats, they are plain TIFF apart from
+/// the magic number (Olympus ORF, Panasonic RW2).
+const TIFF_LIKE_MAGIC: [&[u8; 4]; 4] = [b"IIRO", b"IIRS", b"MMOR", b"IIU\0"];
+
+/// Fujifilm RAF stores a complete JPEG preview, with its EXIF, at an offset
+/// given in the header.
+const RAF_MAGIC: &[u8] = b"FUJIFILMCCD-RAW";
+const RAF_JPEG_OFFSET: u64 = 84;
+
+fn read_exif(path: &Path) -> std::result::Result<exif::Exif, exif::Error> {
+    let mut file = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 16];
+    let n = file.read(&mut magic)?;
+    let magic = &magic[..n];
+    file.seek(SeekFrom::Start(0))?;
+
+    if TIFF_LIKE_MAGIC.iter().any(|m| magic.starts_with(*m)) {
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let le = buf[0] == b'I';
+        buf[2..4].copy_from_slice(if le { &[42, 0] } else { &[0, 42] });
+        return Reader::new().read_raw(buf);
+    }
+
+    if magic.starts_with(RAF_MAGIC) {
+        file.seek(SeekFrom::Start(RAF_JPEG_OFFSET))?;
+        let mut offset = [0u8; 4];
+        file.read_exact(&mut offset)?;
+        file.seek(SeekFrom::Start(u32::from_be_bytes(offset).into()))?;
+        return exif::get_exif_attr_from_jpeg(&mut file)
+            .and_then(|buf| Reader::new().read_raw(buf));
+    }
+
+    Reader::new().read_from_container(&mut file)
+}
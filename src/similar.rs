@@ -0,0 +1,81 @@
+/// Number of differing bits between two perceptual hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// BK-tree of perceptual hashes, finds every hash within a Hamming distance
+/// of another without comparing it to all of them.
+pub struct BkTree<T> {
+    nodes: Vec<Node<T>>,
+}
+
+struct Node<T> {
+    hash: u64,
+    value: T,
+    /// Distance to this node, index of the child.
+    children: Vec<(u32, usize)>,
+}
+
+impl<T> Default for BkTree<T> {
+    fn default() -> Self {
+        BkTree { nodes: vec![] }
+    }
+}
+
+impl<T> BkTree<T> {
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn insert(&mut self, hash: u64, value: T) {
+        let new = self.nodes.len();
+        if new > 0 {
+            let mut current = 0;
+            loop {
+                let d = distance(self.nodes[current].hash, hash);
+                let node = &mut self.nodes[current];
+                match node.children.iter().find(|(child_d, _)| *child_d == d) {
+                    Some(&(_, child)) => current = child,
+                    None => {
+                        node.children.push((d, new));
+                        break;
+                    }
+                }
+            }
+        }
+        self.nodes.push(Node {
+            hash,
+            value,
+            children: vec![],
+        });
+    }
+
+    /// Values whose hash is at most `max_distance` from `hash`, with their
+    /// distance, in no particular order.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(u32, &T)> {
+        let mut found = vec![];
+        let mut stack = match self.nodes.is_empty() {
+            true => vec![],
+            false => vec![0],
+        };
+        while let Some(current) = stack.pop() {
+            let node = &self.nodes[current];
+            let d = distance(node.hash, hash);
+            if d <= max_distance {
+                found.push((d, &node.value));
+            }
+            // by the triangle inequality only these subtrees can hold a match
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(child_d, _)| child_d.abs_diff(d) <= max_distance)
+                    .map(|(_, child)| *child),
+            );
+        }
+        found
+    }
+}
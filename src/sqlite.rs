@@ -1,9 +1,18 @@
-use crate::common::{FsOpCallback, Zoned};
-use std::{path::PathBuf, vec};
+use crate::common::{
+    FileIdentity, FsOp, FsOpCallback, ScanReport, Zoned, below, path_bytes, path_from_bytes,
+    time_zone_from_string, time_zone_string,
+};
+use crate::similar::BkTree;
+use std::collections::HashMap;
+use std::{
+    path::{Path, PathBuf},
+    vec,
+};
 
 use crate::common::{BasicPicture, PictureRecord, Store, StoreReader};
-use anyhow::Result;
-use rusqlite::{Connection, Rows, Statement};
+use anyhow::{Result, anyhow, bail};
+use rusqlite::types::Value;
+use rusqlite::{Connection, OptionalExtension, Rows, Statement, Transaction};
 pub struct SaveToSqlite {
     conn: Connection,
     path: PathBuf,
@@ -11,7 +20,10 @@ pub struct SaveToSqlite {
 
 pub struct SqliteWriter {
     conn: Connection,
-    queue: vec::Vec<PictureRecord>,
+    queue: vec::Vec<FsOp>,
+    dropped: Vec<(PathBuf, anyhow::Error)>,
+    /// No flush is tried on a push before, after one failed.
+    next_flush: Option<std::time::Instant>,
 }
 
 pub struct SqliteReader {
@@ -41,27 +53,242 @@ unsafe impl Send for SqliteResult {}
 
 impl SaveToSqlite {
     pub fn new(path: PathBuf) -> Result<Self> {
-        let conn = Connection::open(path.clone())?;
-        conn.execute(
-            "create table if not exists records(path,fs_create_time_timestamp, fs_create_time_timezone)",
-            (),
-        )
-        .expect("create ok");
+        let mut conn = Connection::open(path.clone())?;
+        migrate(&mut conn)?;
         conn.execute_batch("PRAGMA journal_mode=WAL;")
             .expect("wal ok");
         Ok(SaveToSqlite { conn, path })
     }
 }
+
+/// Schema upgrades, the one at index `i` brings a store from `user_version`
+/// `i` to `i + 1`. Append new ones, never change released ones.
+const MIGRATIONS: &[fn(&Transaction) -> Result<()>] = &[
+    migrate_typed_columns,
+    migrate_sort_indexes,
+    migrate_microseconds,
+];
+
+/// Brings the store up to the latest schema, each upgrade in its own
+/// transaction. Stores from a newer build are refused rather than guessed at.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("pragma user_version", (), |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "store schema version {version} is newer than {}, the latest this build knows",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", from + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Version 1: typed columns, an integer primary key, timestamps as
+/// nanoseconds so they sort as numbers, and an index for every order.
+/// Stores from before versioning are converted.
+fn migrate_typed_columns(tx: &Transaction) -> Result<()> {
+    let legacy_records = table_exists(tx, "records")?;
+    if legacy_records {
+        // the first stores had only the path and the creation time
+        for column in LEGACY_COLUMNS {
+            add_column_if_missing(tx, "records", column)?;
+        }
+        tx.execute_batch(
+            "alter table records rename to legacy_records;
+            drop index if exists records_path;
+            drop index if exists records_content_hash;",
+        )?;
+    }
+    let legacy_reports = table_exists(tx, "scan_reports")?;
+    if legacy_reports {
+        tx.execute_batch("alter table scan_reports rename to legacy_scan_reports")?;
+    }
+
+    tx.execute_batch(
+        "create table records(
+            id integer primary key,
+            path blob not null unique,
+            media_type text not null,
+            size integer not null,
+            inode integer not null,
+            fs_create_time_timestamp integer not null,
+            fs_create_time_timezone text not null,
+            fs_create_time_source text not null,
+            fs_modify_time_timestamp integer not null,
+            fs_modify_time_timezone text not null,
+            exif_create_time_timestamp integer,
+            exif_create_time_timezone text,
+            content_hash blob,
+            perceptual_hash integer,
+            missing integer not null default 0
+        );
+        create index records_fs_create_time on records(fs_create_time_timestamp);
+        create index records_fs_modify_time on records(fs_modify_time_timestamp);
+        create index records_exif_create_time on records(exif_create_time_timestamp);
+        create index records_content_hash on records(content_hash);
+        create table scan_reports(
+            id integer primary key,
+            root blob not null,
+            finished integer not null,
+            report text not null
+        );
+        create index scan_reports_root on scan_reports(root, finished);",
+    )?;
+
+    if legacy_records {
+        let mut select = tx.prepare(
+            "select rowid, path, media_type, coalesce(size, 0), coalesce(inode, 0), fs_create_time_timestamp, fs_create_time_timezone, coalesce(fs_create_time_source, 'Birth'), coalesce(fs_modify_time_timestamp, fs_create_time_timestamp), coalesce(fs_modify_time_timezone, fs_create_time_timezone), exif_create_time_timestamp, exif_create_time_timezone, content_hash, perceptual_hash, coalesce(missing, 0) from legacy_records order by rowid desc",
+        )?;
+        let mut insert = tx.prepare(
+            "insert into records(id, path, media_type, size, inode, fs_create_time_timestamp, fs_create_time_timezone, fs_create_time_source, fs_modify_time_timestamp, fs_modify_time_timezone, exif_create_time_timestamp, exif_create_time_timezone, content_hash, perceptual_hash, missing) values (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15) on conflict(path) do nothing",
+        )?;
+        let mut rows = select.query(())?;
+        while let Some(row) = rows.next()? {
+            let mut values: Vec<Value> = (0..15).map(|i| row.get(i)).collect::<Result<_, _>>()?;
+            // paths used to be text and timestamps RFC 3339 text
+            if let Value::Text(path) = &values[1] {
+                values[1] = Value::Blob(path.as_bytes().to_vec());
+            }
+            // a zero size and inode have the next rescan extract it again
+            if let (Value::Null, Value::Blob(path)) = (&values[2], &values[1])
+                && let Some(media_type) = legacy_media_type(path)
+            {
+                values[2] = Value::Text(media_type.as_ref().to_owned());
+            }
+            let mut readable = true;
+            for i in [5, 8, 10] {
+                match &values[i] {
+                    Value::Text(timestamp) => match timestamp.parse() {
+                        Ok(timestamp) => values[i] = Value::Integer(nanoseconds(timestamp)?),
+                        Err(_) => readable = false,
+                    },
+                    Value::Null => {}
+                    _ => readable = false,
+                }
+            }
+            if NOT_NULL_COLUMNS.iter().any(|&i| values[i] == Value::Null) {
+                readable = false;
+            }
+            // nothing could read it back before either; stores from before
+            // paths were unique keep only their newest row, copied first
+            if readable {
+                insert.execute(rusqlite::params_from_iter(values))?;
+            }
+        }
+        tx.execute_batch("drop table legacy_records")?;
+    }
+
+    if legacy_reports {
+        let mut select = tx.prepare("select root, finished, report from legacy_scan_reports")?;
+        let mut rows = select.query(())?;
+        while let Some(row) = rows.next()? {
+            let root = match row.get(0)? {
+                Value::Text(root) => root.into_bytes(),
+                Value::Blob(root) => root,
+                _ => continue,
+            };
+            let Ok(finished) = row.get::<_, String>(1)?.parse() else {
+                continue;
+            };
+            tx.execute(
+                "insert into scan_reports(root, finished, report) values (?1, ?2, ?3)",
+                (root, nanoseconds(finished)?, row.get::<_, String>(2)?),
+            )?;
+        }
+        tx.execute_batch("drop table legacy_scan_reports")?;
+    }
+    Ok(())
+}
+
+/// Columns of the records table before versioning, which stores added as
+/// they went.
+const LEGACY_COLUMNS: [&str; 14] = [
+    "path",
+    "media_type",
+    "size",
+    "inode",
+    "fs_create_time_timestamp",
+    "fs_create_time_timezone",
+    "fs_create_time_source",
+    "fs_modify_time_timestamp",
+    "fs_modify_time_timezone",
+    "exif_create_time_timestamp",
+    "exif_create_time_timezone",
+    "content_hash",
+    "perceptual_hash",
+    "missing",
+];
+
+/// Values `migrate_typed_columns` copies which the typed table requires.
+const NOT_NULL_COLUMNS: [usize; 10] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 14];
+
+/// Media type of a record stored without one, from its path's extension.
+/// `None` for files which are not media, and not indexed nowadays.
+fn legacy_media_type(path: &[u8]) -> Option<crate::media::MediaType> {
+    let extension = path_from_bytes(path.to_vec())
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    crate::media::detect_bytes(&[], &extension)
+}
+
+/// Version 2: indexes ordered like the `order_terms` of capture time, whose
+/// missing values come last in both directions. Other keys are not null,
+/// their index is walked backwards for a descending sort.
+fn migrate_sort_indexes(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "create index records_exif_create_time_order on records(exif_create_time_timestamp is null, exif_create_time_timestamp, id);
+        create index records_exif_create_time_order_desc on records(exif_create_time_timestamp is null, exif_create_time_timestamp desc, id desc);",
+    )?;
+    Ok(())
+}
+
+/// Nanoseconds since the epoch, the timestamps of versions 1 and 2, which
+/// cover the years 1678 to 2261.
+fn nanoseconds(timestamp: jiff::Timestamp) -> Result<i64> {
+    Ok(i64::try_from(timestamp.as_nanosecond())?)
+}
+
+/// Version 3: timestamps as microseconds like the delta store, so files
+/// from any year jiff knows are kept, such as the 1601 modification times
+/// extracted archives leave. Truncated toward zero as jiff does.
+fn migrate_microseconds(tx: &Transaction) -> Result<()> {
+    tx.execute_batch(
+        "update records set fs_create_time_timestamp = fs_create_time_timestamp / 1000,
+            fs_modify_time_timestamp = fs_modify_time_timestamp / 1000,
+            exif_create_time_timestamp = exif_create_time_timestamp / 1000;
+        update scan_reports set finished = finished / 1000;",
+    )?;
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    Ok(conn.query_row(
+        "select count(*) > 0 from sqlite_master where type = 'table' and name = ?1",
+        [table],
+        |row| row.get(0),
+    )?)
+}
+
 impl Store for SaveToSqlite {
     fn writer(self: &Self) -> impl FsOpCallback {
         let ret = SqliteWriter {
             conn: Connection::open(self.path.clone()).expect("open"),
             queue: vec![],
+            dropped: vec![],
+            next_flush: None,
         };
 
         ret.conn
             .execute_batch("PRAGMA journal_mode=WAL;")
             .expect("wal ok");
+        ret.conn.busy_timeout(BUSY_TIMEOUT).expect("busy timeout");
 
         ret
     }
@@ -74,54 +301,323 @@ impl Store for SaveToSqlite {
     }
 }
 
-fn as_sqlite_tuple(record: &PictureRecord) -> Result<(&str, String, String)> {
-    let fs_create_time_timestamp = serde_json::to_value(&record.fs_create_time.0.timestamp())?;
+/// Microseconds since the epoch, which hold every time jiff does.
+fn timestamp_column(timestamp: jiff::Timestamp) -> i64 {
+    timestamp.as_microsecond()
+}
 
-    let fs_create_time_timestamp = fs_create_time_timestamp.as_str().expect("as_str");
+fn timestamp_from_column(microseconds: i64) -> Result<jiff::Timestamp> {
+    Ok(jiff::Timestamp::from_microsecond(microseconds)?)
+}
+
+fn zoned_columns(zoned: &Zoned) -> Result<(i64, String)> {
+    let timestamp = timestamp_column(zoned.0.timestamp());
+
+    Ok((timestamp, time_zone_string(&zoned.0)))
+}
+
+type SqliteTuple<'a> = (
+    &'a [u8],
+    &'a str,
+    u64,
+    u64,
+    i64,
+    String,
+    i64,
+    String,
+    Option<i64>,
+    Option<String>,
+    &'a str,
+    &'a [u8],
+    Option<i64>,
+);
+
+fn as_sqlite_tuple(record: &PictureRecord) -> Result<SqliteTuple<'_>> {
+    let (fs_create_time_timestamp, fs_create_time_timezone) =
+        zoned_columns(&record.fs_create_time)?;
+
+    let (fs_modify_time_timestamp, fs_modify_time_timezone) =
+        zoned_columns(&record.fs_modify_time)?;
+
+    let (exif_create_time_timestamp, exif_create_time_timezone) = match &record.exif_create_time {
+        Some(exif_create_time) => {
+            let (timestamp, timezone) = zoned_columns(exif_create_time)?;
+            (Some(timestamp), Some(timezone))
+        }
+        None => (None, None),
+    };
 
-    let fs_create_time_timezone = record
-        .fs_create_time
-        .0
-        .time_zone()
-        .iana_name()
-        .expect("no timezone"); //TODO
     Ok((
-        &record.path,
-        fs_create_time_timestamp.to_owned(),
-        fs_create_time_timezone.to_owned(),
+        path_bytes(&record.path),
+        record.media_type.as_ref(),
+        record.size,
+        record.inode,
+        fs_create_time_timestamp,
+        fs_create_time_timezone,
+        fs_modify_time_timestamp,
+        fs_modify_time_timezone,
+        exif_create_time_timestamp,
+        exif_create_time_timezone,
+        record.fs_create_time_source.as_ref(),
+        &record.content_hash,
+        // sqlite integers are signed, the bits are what matters
+        record.perceptual_hash.map(|hash| hash as i64),
     ))
 }
 
-impl FsOpCallback for SqliteWriter {
-    fn on_op(&mut self, entry: PictureRecord) -> Result<()> {
-        self.queue.push(entry);
+/// Adds `column` to a table from before versioning which lacks it.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "select count(*) > 0 from pragma_table_info(?1) where name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("alter table {table} add column {column}"), ())?;
+    }
+    Ok(())
+}
+
+/// Insert a record, or refresh the stored one at the same path.
+const UPSERT_RECORD: &str = "insert into records(path, media_type, size, inode, fs_create_time_timestamp, fs_create_time_timezone, fs_modify_time_timestamp, fs_modify_time_timezone, exif_create_time_timestamp, exif_create_time_timezone, missing, fs_create_time_source, content_hash, perceptual_hash) values (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,0,?11,?12,?13)
+    on conflict(path) do update set missing = 0, media_type = excluded.media_type, size = excluded.size, inode = excluded.inode, fs_create_time_timestamp = excluded.fs_create_time_timestamp, fs_create_time_timezone = excluded.fs_create_time_timezone, fs_modify_time_timestamp = excluded.fs_modify_time_timestamp, fs_modify_time_timezone = excluded.fs_modify_time_timezone, exif_create_time_timestamp = excluded.exif_create_time_timestamp, exif_create_time_timezone = excluded.exif_create_time_timezone, fs_create_time_source = excluded.fs_create_time_source, content_hash = excluded.content_hash, perceptual_hash = excluded.perceptual_hash";
+
+/// Queued changes beyond which a push flushes the whole queue.
+const FLUSH_BATCH: usize = 1000;
+
+/// Queued changes kept while the database can't be written, beyond that
+/// they are dropped and reported.
+const MAX_QUEUE: usize = 100 * FLUSH_BATCH;
 
-        if self.queue.len() > 1000 {
-            self.flush()?
+/// How long a statement waits for another writer to let go of the database.
+pub(crate) const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Attempts of a flush while the database is busy, waiting twice as long
+/// after each one starting from `FLUSH_BACKOFF`, so a flush gives up after
+/// about two seconds.
+const FLUSH_RETRIES: u32 = 3;
+const FLUSH_BACKOFF: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// How long pushes queue up without trying to flush after a failed flush,
+/// rather than each waiting out the busy database again.
+const FLUSH_PAUSE: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Busy or locked by another connection, worth trying again later.
+fn is_busy(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+/// A failure of this change alone, such as a value sqlite can't hold,
+/// trying it again won't help.
+fn is_rejected(error: &rusqlite::Error) -> bool {
+    match error.sqlite_error_code() {
+        Some(code) => matches!(
+            code,
+            rusqlite::ErrorCode::ConstraintViolation | rusqlite::ErrorCode::TypeMismatch
+        ),
+        None => true,
+    }
+}
+
+fn execute_op(tx: &Transaction, op: &FsOp) -> Result<()> {
+    match op {
+        FsOp::Upsert(entry) => {
+            tx.execute(UPSERT_RECORD, as_sqlite_tuple(entry)?)?;
+        }
+        FsOp::Remove(path) => {
+            tx.execute("delete from records where path = ?1", [path_bytes(path)])?;
+        }
+        FsOp::Missing(path) => {
+            tx.execute(
+                "update records set missing = 1 where path = ?1",
+                [path_bytes(path)],
+            )?;
+        }
+        FsOp::Move { from, to, identity } => {
+            tx.execute(
+                "update or replace records set path = ?2, size = ?3, inode = ?4, fs_modify_time_timestamp = ?5, missing = 0 where path = ?1",
+                (
+                    path_bytes(from),
+                    path_bytes(to),
+                    identity.size,
+                    identity.inode,
+                    timestamp_column(identity.modify_time),
+                ),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+impl SqliteWriter {
+    fn push(&mut self, op: FsOp) -> Result<()> {
+        self.queue.push(op);
+        if self.queue.len() <= FLUSH_BATCH {
+            return Ok(());
+        }
+
+        let paused = self
+            .next_flush
+            .is_some_and(|next_flush| std::time::Instant::now() < next_flush);
+        let error = match paused {
+            true => anyhow!("paused after a failed flush"),
+            false => match self.flush() {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            },
+        };
+        if self.queue.len() >= MAX_QUEUE {
+            self.drop_queued(&error);
         }
 
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.conn.execute_batch("begin transaction")?;
-        for entry in self.queue.iter() {
-            if let Ok(tup) = as_sqlite_tuple(entry) {
-                self.conn
-                    .execute(
-                        "insert into records(path, fs_create_time_timestamp, fs_create_time_timezone) values (?1,?2,?3)",
-                        tup,
-                    )
-                    .expect("insert ok");
+    /// Writes the whole queue in one transaction, rolled back on any failure
+    /// but a change rejected on its own, which is dropped and reported.
+    fn try_flush(&mut self) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        let mut rejected = vec![];
+        for (i, op) in self.queue.iter().enumerate() {
+            if let Err(error) = execute_op(&tx, op) {
+                match error.downcast_ref::<rusqlite::Error>() {
+                    Some(e) if !is_rejected(e) => return Err(error),
+                    _ => rejected.push((i, error)),
+                }
             }
         }
-        self.conn.execute_batch("commit;").expect("commit ok");
-        //TODO: lose queue content or grow infinitely
+        tx.commit()?;
+
+        for (i, error) in rejected.into_iter().rev() {
+            let op = self.queue.remove(i);
+            self.dropped.push((op.path().to_owned(), error));
+        }
         self.queue.clear();
         Ok(())
     }
 }
 
+impl FsOpCallback for SqliteWriter {
+    fn on_op(&mut self, entry: PictureRecord) -> Result<()> {
+        self.push(FsOp::Upsert(Box::new(entry)))
+    }
+
+    fn on_remove(&mut self, path: &Path) -> Result<()> {
+        self.push(FsOp::Remove(path.to_owned()))
+    }
+
+    fn on_missing(&mut self, path: &Path) -> Result<()> {
+        self.push(FsOp::Missing(path.to_owned()))
+    }
+
+    fn on_move(&mut self, from: &Path, to: &Path, identity: FileIdentity) -> Result<()> {
+        self.push(FsOp::Move {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            identity,
+        })
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        let mut attempt = 1;
+        let mut backoff = FLUSH_BACKOFF;
+        loop {
+            match self.try_flush() {
+                Err(error)
+                    if attempt < FLUSH_RETRIES
+                        && error.downcast_ref::<rusqlite::Error>().is_some_and(is_busy) =>
+                {
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => {
+                    self.next_flush = result
+                        .is_err()
+                        .then(|| std::time::Instant::now() + FLUSH_PAUSE);
+                    return result;
+                }
+            }
+        }
+    }
+
+    fn take_dropped(&mut self) -> Vec<(PathBuf, anyhow::Error)> {
+        std::mem::take(&mut self.dropped)
+    }
+
+    fn drop_queued(&mut self, error: &anyhow::Error) {
+        for op in self.queue.drain(..) {
+            self.dropped
+                .push((op.path().to_owned(), anyhow!("not flushed: {error:#}")));
+        }
+    }
+
+    fn on_report(&mut self, root: &Path, report: &ScanReport) -> Result<()> {
+        self.conn.execute(
+            "insert into scan_reports(root, finished, report) values (?1, ?2, ?3)",
+            (
+                path_bytes(root),
+                timestamp_column(jiff::Timestamp::now()),
+                serde_json::to_string(report)?,
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn stored_content_hash(&mut self, path: &Path) -> Result<Option<[u8; 32]>> {
+        let hash: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "select content_hash from records where path = ?1",
+                [path_bytes(path)],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(hash.and_then(|hash| hash.try_into().ok()))
+    }
+
+    fn stored_identities(&mut self, root: &Path) -> Result<HashMap<PathBuf, FileIdentity>> {
+        let prefix = below(root);
+
+        let mut stmt = self.conn.prepare(
+            "select path, size, inode, fs_modify_time_timestamp from records where not missing and (path = ?1 or substr(path, 1, length(?2)) = ?2)",
+        )?;
+        let mut rows = stmt.query((path_bytes(root), prefix))?;
+
+        let mut ret = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let (Ok(path), Ok(size), Ok(inode), Ok(modify_time)) = (
+                row.get::<_, Vec<u8>>(0),
+                row.get::<_, u64>(1),
+                row.get::<_, u64>(2),
+                row.get::<_, i64>(3),
+            ) else {
+                continue;
+            };
+            let Ok(modify_time) = timestamp_from_column(modify_time) else {
+                continue;
+            };
+            ret.insert(
+                path_from_bytes(path),
+                FileIdentity {
+                    size,
+                    inode,
+                    modify_time,
+                },
+            );
+        }
+        Ok(ret)
+    }
+}
+
 impl SqliteReader {
     pub fn new(path: PathBuf) -> Result<Self> {
         let conn = Connection::open(path.clone())?;
@@ -130,28 +626,39 @@ impl SqliteReader {
             .expect("wal ok");
         Ok(SqliteReader { conn, path })
     }
-}
 
-use crate::common::OrderBy;
-impl StoreReader for SqliteReader {
-    fn load(
-        &mut self,
-        order_by: OrderBy,
-        limit: usize,
-    ) -> Result<impl Iterator<Item = BasicPicture>> {
+    /// Reports persisted by walks of `root`, latest first.
+    pub fn scan_reports(&self, root: &Path) -> Result<Vec<ScanReport>> {
+        let mut stmt = self.conn.prepare(
+            "select report from scan_reports where root = ?1 order by finished desc, id desc",
+        )?;
+        stmt.query_map([path_bytes(root)], |row| row.get::<_, String>(0))?
+            .map(|report| Ok(serde_json::from_str(&report?)?))
+            .collect()
+    }
+
+    /// `load` without borrowing the reader, the result has a connection of
+    /// its own.
+    pub fn query(&self, sort: Sort, filter: Filter, limit: usize) -> Result<SqliteResult> {
         let conn = Connection::open(self.path.clone())?;
+        if let Some(after) = &filter.after
+            && after.sort != sort
+        {
+            bail!("cursor of {} used to list by {sort}", after.sort);
+        }
+        let (where_clause, params) = where_clause(&filter)?;
+        // sqlite takes no limit beyond an i64
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
         Ok(SqliteResultBuilder {
             error_count: 0,
             conn_stmt: ConnStmtBuilder {
                 conn,
                 stmt_builder: |conn: &Connection| {
                     conn.prepare(
-                        &("SELECT * from records order by  ".to_owned()
-                            + match order_by {
-                                OrderBy::FsCreateTime => "fs_create_time_timestamp",
-                                OrderBy::FsModifyTime => "fs_create_time_timestamp", // TODO
-                                OrderBy::ExifCreateTime => "fs_create_time_timestamp", // TODO
-                            }
+                        &("SELECT path, media_type, fs_create_time_timestamp, fs_create_time_timezone, exif_create_time_timestamp, exif_create_time_timezone, fs_create_time_source, fs_modify_time_timestamp, fs_modify_time_timezone, id from records".to_owned()
+                            + &where_clause
+                            + " order by "
+                            + &order_terms(&sort)
                             + &match limit {
                                 0 => "".to_owned(),
                                 _ => " limit ".to_owned() + &limit.to_string(),
@@ -163,7 +670,7 @@ impl StoreReader for SqliteReader {
             .build(),
             rows_builder: |conn_stmt: &mut ConnStmt| {
                 conn_stmt
-                    .with_stmt_mut(|stmt| stmt.query([]))
+                    .with_stmt_mut(|stmt| stmt.query(rusqlite::params_from_iter(params)))
                     .expect("query")
             },
         }
@@ -171,6 +678,335 @@ impl StoreReader for SqliteReader {
     }
 }
 
+use crate::common::{
+    Cursor, DuplicateGroup, Filter, Granularity, OrderBy, Sort, SortValue, TimeSource,
+    TimelineBucket,
+};
+use crate::media::MediaType;
+use std::str::FromStr;
+impl StoreReader for SqliteReader {
+    fn load(
+        &mut self,
+        sort: Sort,
+        filter: Filter,
+        limit: usize,
+    ) -> Result<impl Iterator<Item = BasicPicture>> {
+        self.query(sort, filter, limit)
+    }
+
+    fn timeline(
+        &mut self,
+        key: OrderBy,
+        granularity: Granularity,
+        filter: Filter,
+    ) -> Result<Vec<TimelineBucket>> {
+        let (column, zone_column) = (key_column(key), zone_column(key)?);
+        let (where_clause, params) = where_clause(&filter)?;
+        // minutes are counted here, the days they fall on are worked out
+        // below where time zones are known
+        let mut stmt = self.conn.prepare(&format!(
+            "select {zone_column}, min({column}), count(*) from records{where_clause} and {column} is not null
+            group by {zone_column}, ({column} - ((({column} % {TIMELINE_STEP}) + {TIMELINE_STEP}) % {TIMELINE_STEP})) / {TIMELINE_STEP}"
+        ))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(params))?;
+
+        let mut zones: HashMap<String, jiff::tz::TimeZone> = HashMap::new();
+        let mut counts: std::collections::BTreeMap<jiff::civil::Date, u64> = Default::default();
+        while let Some(row) = rows.next()? {
+            let zone: String = row.get(0)?;
+            let zone = match zones.get(&zone) {
+                Some(zone) => zone.clone(),
+                None => {
+                    // unreadable the same way `load` can't return them
+                    let Ok(found) = time_zone_from_string(&zone) else {
+                        continue;
+                    };
+                    zones.entry(zone).or_insert(found).clone()
+                }
+            };
+            let time = timestamp_from_column(row.get(1)?)?.to_zoned(zone);
+            *counts.entry(granularity.start(time.date())).or_default() += row.get::<_, u64>(2)?;
+        }
+
+        Ok(counts
+            .into_iter()
+            .map(|(start, count)| TimelineBucket { start, count })
+            .collect())
+    }
+
+    fn duplicates(&mut self, limit: usize) -> Result<Vec<DuplicateGroup>> {
+        let mut stmt = self.conn.prepare(
+            "select content_hash, size, path from records where not missing and content_hash in (
+                select content_hash from records where not missing and content_hash is not null
+                group by content_hash having count(*) > 1 order by max(size) desc, content_hash limit ?1)
+            order by size desc, content_hash, path",
+        )?;
+        // a negative limit is none
+        let limit = match limit {
+            0 => -1,
+            _ => limit as i64,
+        };
+        let mut rows = stmt.query([limit])?;
+
+        let mut groups: Vec<DuplicateGroup> = vec![];
+        while let Some(row) = rows.next()? {
+            let content_hash: [u8; 32] = row.get(0)?;
+            let content_hash = blake3::Hash::from_bytes(content_hash).to_hex().to_string();
+            let path = path_from_bytes(row.get(2)?);
+            match groups.last_mut() {
+                Some(group) if group.content_hash == content_hash => group.paths.push(path),
+                _ => groups.push(DuplicateGroup {
+                    content_hash,
+                    size: row.get(1)?,
+                    paths: vec![path],
+                }),
+            }
+        }
+        Ok(groups)
+    }
+}
+
+/// Nanoseconds in the unit `timeline` groups records by before counting
+/// them in their time zone, time zone offsets are whole minutes.
+const TIMELINE_STEP: i64 = 60_000_000_000;
+
+/// Column holding the sort key of `order_by`.
+fn key_column(order_by: OrderBy) -> &'static str {
+    match order_by {
+        OrderBy::FsCreateTime => "fs_create_time_timestamp",
+        OrderBy::FsModifyTime => "fs_modify_time_timestamp",
+        OrderBy::ExifCreateTime => "exif_create_time_timestamp",
+        OrderBy::Path => "path",
+    }
+}
+
+/// Whether records may lack the key, they are ordered last.
+fn nullable(order_by: OrderBy) -> bool {
+    order_by == OrderBy::ExifCreateTime
+}
+
+/// Sql `order by` terms for `sort`, written so the indexes of
+/// `migrate_sort_indexes` serve them.
+fn order_terms(sort: &Sort) -> String {
+    let direction = |descending| if descending { " desc" } else { "" };
+    let mut terms = vec![];
+    for key in &sort.0 {
+        let column = key_column(key.order_by);
+        if nullable(key.order_by) {
+            terms.push(format!("{column} is null"));
+        }
+        terms.push(format!("{column}{}", direction(key.descending)));
+    }
+    terms.push(format!("id{}", direction(sort.descending_id())));
+    terms.join(", ")
+}
+
+/// Condition for the records ordered after `after`: any key ordered after
+/// while the keys before it are equal, or all keys equal and the id after.
+fn after_condition(after: &Cursor) -> Result<(String, Vec<Value>)> {
+    let mut alternatives = vec![];
+    let mut params = vec![];
+    let (mut equal, mut equal_params) = (vec![], vec![]);
+    for (key, value) in after.sort.0.iter().zip(&after.values) {
+        let column = key_column(key.order_by);
+        let value = match (key.order_by, value) {
+            (OrderBy::Path, SortValue::Path(path)) => Value::Blob(path_bytes(path).to_vec()),
+            (OrderBy::Path, _) | (_, SortValue::Path(_)) => {
+                bail!("cursor values don't fit its sort")
+            }
+            (_, SortValue::Time(Some(time))) => Value::Integer(timestamp_column(*time)),
+            // only records missing it too, and none is ordered after them
+            (_, SortValue::Time(None)) => {
+                equal.push(format!("{column} is null"));
+                continue;
+            }
+        };
+        let op = if key.descending { "<" } else { ">" };
+        let later = match nullable(key.order_by) {
+            true => format!("({column} is null or {column} {op} ?)"),
+            false => format!("{column} {op} ?"),
+        };
+        alternatives.push([equal.as_slice(), &[later]].concat().join(" and "));
+        params.extend(equal_params.iter().cloned());
+        params.push(value.clone());
+        equal.push(format!("{column} = ?"));
+        equal_params.push(value);
+    }
+    let op = if after.sort.descending_id() { "<" } else { ">" };
+    alternatives.push(
+        [equal.as_slice(), &[format!("id {op} ?")]]
+            .concat()
+            .join(" and "),
+    );
+    params.extend(equal_params);
+    params.push(Value::Integer(after.id));
+
+    Ok((format!("(({}))", alternatives.join(") or (")), params))
+}
+
+/// Column holding the time zone of the time `key`.
+fn zone_column(key: OrderBy) -> Result<&'static str> {
+    Ok(match key {
+        OrderBy::FsCreateTime => "fs_create_time_timezone",
+        OrderBy::FsModifyTime => "fs_modify_time_timezone",
+        OrderBy::ExifCreateTime => "exif_create_time_timezone",
+        OrderBy::Path => bail!("path is not a time"),
+    })
+}
+
+/// Translates `filter` into a sql where clause and its bound parameters.
+fn where_clause(filter: &Filter) -> Result<(String, Vec<Value>)> {
+    if filter.as_of.is_some() {
+        bail!("sqlite keeps no history");
+    }
+    let mut conditions = vec!["not missing".to_owned()];
+    let mut params = vec![];
+
+    if !filter.media_types.is_empty() {
+        let placeholders = vec!["?"; filter.media_types.len()].join(",");
+        conditions.push(format!("media_type in ({placeholders})"));
+        params.extend(
+            filter
+                .media_types
+                .iter()
+                .map(|m| Value::Text(m.as_ref().to_owned())),
+        );
+    }
+
+    // one json array rather than a variable per id, which are limited
+    if let Some(ids) = &filter.ids {
+        conditions.push("id in (select value from json_each(?))".to_owned());
+        params.push(Value::Text(serde_json::to_string(ids)?));
+    }
+
+    if let Some(after) = &filter.after {
+        let (condition, after_params) = after_condition(after)?;
+        conditions.push(condition);
+        params.extend(after_params);
+    }
+
+    if let Some(range) = &filter.time_range {
+        if range.key == OrderBy::Path {
+            bail!("path is not a time");
+        }
+        let column = key_column(range.key);
+        conditions.push(format!("{column} is not null"));
+        if let Some(since) = range.since {
+            conditions.push(format!("{column} >= ?"));
+            params.push(Value::Integer(timestamp_column(since)));
+        }
+        if let Some(until) = range.until {
+            conditions.push(format!("{column} < ?"));
+            params.push(Value::Integer(timestamp_column(until)));
+        }
+    }
+
+    if let Some(path_prefix) = &filter.path_prefix {
+        let prefix = below(path_prefix);
+        conditions.push("(path = ? or substr(path, 1, length(?)) = ?)".to_owned());
+        params.push(Value::Blob(path_bytes(path_prefix).to_vec()));
+        params.push(Value::Blob(prefix.clone()));
+        params.push(Value::Blob(prefix));
+    }
+
+    if let Some(glob) = &filter.path_glob {
+        conditions.push("cast(path as text) glob ?".to_owned());
+        params.push(Value::Text(glob.clone()));
+    }
+
+    if let Some(min_size) = filter.min_size {
+        conditions.push("size >= ?".to_owned());
+        params.push(Value::Integer(min_size.try_into().unwrap_or(i64::MAX)));
+    }
+    if let Some(max_size) = filter.max_size {
+        conditions.push("size <= ?".to_owned());
+        params.push(Value::Integer(max_size.try_into().unwrap_or(i64::MAX)));
+    }
+
+    Ok((" where ".to_owned() + &conditions.join(" and "), params))
+}
+
+/// Perceptual hashes of the present records in a `BkTree`, rebuilt when
+/// another connection changed the store since the last query.
+pub struct SqliteSimilarIndex {
+    conn: Connection,
+    data_version: Option<i64>,
+    tree: BkTree<i64>,
+}
+
+impl SqliteSimilarIndex {
+    pub fn new(path: PathBuf) -> Result<Self> {
+        Ok(SqliteSimilarIndex {
+            conn: Connection::open(path)?,
+            data_version: None,
+            tree: BkTree::default(),
+        })
+    }
+
+    /// Ids of the records whose picture is within `max_distance` of the one
+    /// of record `id`, with their distance, closest first. `None` when `id`
+    /// has no perceptual hash.
+    pub fn similar(&mut self, id: i64, max_distance: u32) -> Result<Option<Vec<(i64, u32)>>> {
+        self.refresh()?;
+
+        let hash: Option<i64> = self
+            .conn
+            .query_row(
+                "select perceptual_hash from records where id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        let Some(hash) = hash else {
+            return Ok(None);
+        };
+
+        let mut found: Vec<_> = self
+            .tree
+            .find(hash as u64, max_distance)
+            .into_iter()
+            .filter(|(_, other)| **other != id)
+            .map(|(distance, other)| (*other, distance))
+            .collect();
+        found.sort_by_key(|&(other, distance)| (distance, other));
+        Ok(Some(found))
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        let data_version = self
+            .conn
+            .query_row("pragma data_version", (), |row| row.get(0))?;
+        if self.data_version == Some(data_version) {
+            return Ok(());
+        }
+
+        let mut tree = BkTree::default();
+        let mut stmt = self.conn.prepare(
+            "select id, perceptual_hash from records where not missing and perceptual_hash is not null",
+        )?;
+        let mut rows = stmt.query(())?;
+        while let Some(row) = rows.next()? {
+            tree.insert(row.get::<_, i64>(1)? as u64, row.get(0)?);
+        }
+
+        self.tree = tree;
+        self.data_version = Some(data_version);
+        Ok(())
+    }
+}
+
+/// Reads a timestamp column and the timezone column following it.
+fn zoned_from_columns(row: &rusqlite::Row, idx: usize) -> Option<Zoned> {
+    let timestamp: i64 = row.get(idx).ok()?;
+    let timestamp = timestamp_from_column(timestamp).ok()?;
+
+    let timezone: String = row.get(idx + 1).ok()?;
+    let timezone = time_zone_from_string(&timezone).ok()?;
+
+    Some(Zoned(jiff::Zoned::new(timestamp, timezone)))
+}
+
 const MAX_RETRY: usize = 10;
 impl Iterator for SqliteResult {
     type Item = BasicPicture;
@@ -185,40 +1021,44 @@ impl Iterator for SqliteResult {
                 }
 
                 Ok(Some(row)) => {
-                    let path: std::result::Result<String, _> = row.get(0);
-                    let Ok(path) = path else {
+                    let path: std::result::Result<Vec<u8>, _> = row.get(0);
+                    let Ok(path) = path.map(path_from_bytes) else {
                         continue;
                     };
 
-                    let fs_create_time_timestamp: std::result::Result<String, _> = row.get(1);
-                    let Ok(fs_create_time_timestamp) = fs_create_time_timestamp else {
+                    let media_type: std::result::Result<String, _> = row.get(1);
+                    let Ok(Ok(media_type)) = media_type.map(|m| MediaType::from_str(&m)) else {
                         continue;
                     };
 
-                    let Ok(fs_create_time_timestamp) =
-                        fs_create_time_timestamp.parse::<jiff::Timestamp>()
-                    else {
+                    let Some(fs_create_time) = zoned_from_columns(row, 2) else {
+                        continue;
+                    };
+
+                    let Some(fs_modify_time) = zoned_from_columns(row, 7) else {
                         continue;
                     };
 
-                    let fs_create_time_timezone: std::result::Result<String, _> = row.get(2);
-                    let Ok(fs_create_time_timezone) = fs_create_time_timezone else {
+                    let Ok(id) = row.get(9) else {
                         continue;
                     };
 
-                    let Ok(fs_create_time_timezone) =
-                        jiff::tz::TimeZone::get(&fs_create_time_timezone)
+                    let exif_create_time = zoned_from_columns(row, 4);
+
+                    let source: std::result::Result<String, _> = row.get(6);
+                    let Ok(Ok(fs_create_time_source)) = source.map(|s| TimeSource::from_str(&s))
                     else {
                         continue;
                     };
 
                     return Some(BasicPicture {
+                        id,
                         path,
-                        fs_create_time: Zoned(jiff::Zoned::new(
-                            fs_create_time_timestamp,
-                            fs_create_time_timezone,
-                        )),
-                        exif_create_time: None,
+                        media_type,
+                        fs_create_time,
+                        fs_create_time_source,
+                        fs_modify_time,
+                        exif_create_time,
                     });
                 }
                 _ => {}
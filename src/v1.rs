@@ -0,0 +1,63 @@
+//! Version 1 of the JSON served under `/v1/`, described by `PICTURE_SCHEMA`.
+//! Fields may be added, never renamed, retyped or removed; anything else
+//! needs a version 2.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::common::{BasicPicture, TimeSource, serialize_escaped_path};
+use crate::media::MediaType;
+
+/// JSON Schema of `Picture`, with the `/v1/list` page and the items of
+/// `/v1/similar` and `/v1/changes` under `$defs`.
+pub const PICTURE_SCHEMA: &str = include_str!("../schema/picture.v1.json");
+
+#[derive(Serialize)]
+pub struct Picture<'a> {
+    pub id: i64,
+    #[serde(serialize_with = "serialize_escaped_path")]
+    pub path: &'a Path,
+    pub media_type: MediaType,
+    pub fs_create_time: Time,
+    pub fs_create_time_source: TimeSource,
+    pub fs_modify_time: Time,
+    pub exif_create_time: Option<Time>,
+}
+
+impl<'a> From<&'a BasicPicture> for Picture<'a> {
+    fn from(picture: &'a BasicPicture) -> Self {
+        Picture {
+            id: picture.id,
+            path: &picture.path,
+            media_type: picture.media_type,
+            fs_create_time: Time::from(&picture.fs_create_time.0),
+            fs_create_time_source: picture.fs_create_time_source,
+            fs_modify_time: Time::from(&picture.fs_modify_time.0),
+            exif_create_time: picture.exif_create_time.as_ref().map(|t| Time::from(&t.0)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Time {
+    /// RFC 3339 with the offset in effect, such as `2024-07-01T12:00:00+09:00`.
+    pub time: String,
+    /// IANA name, `None` for a fixed offset or POSIX rule.
+    pub time_zone: Option<String>,
+    /// POSIX TZ rule such as `EST5EDT,M3.2.0,M11.1.0` when the zone is one.
+    pub time_zone_rule: Option<String>,
+}
+
+impl From<&jiff::Zoned> for Time {
+    fn from(zoned: &jiff::Zoned) -> Self {
+        Time {
+            time: zoned
+                .timestamp()
+                .display_with_offset(zoned.offset())
+                .to_string(),
+            time_zone: zoned.time_zone().iana_name().map(str::to_owned),
+            time_zone_rule: crate::common::posix_rule(zoned.time_zone()),
+        }
+    }
+}
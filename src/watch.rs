@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use notify::RecursiveMode;
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify_debouncer_full::{DebouncedEvent, new_debouncer};
+use rocket::{error, warn};
+
+use crate::common::{
+    FileIdentity, FsOpCallback, PictureRecord, ScanReport, WalkOptions, walk_files_until,
+};
+use crate::media;
+
+/// How often `watch_files` checks for settled files and for `stop`.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Debug)]
+pub struct WatchOptions {
+    /// How long events for a path are coalesced before they are handled.
+    pub debounce: Duration,
+    /// How long a file must keep the same size and modification time before
+    /// it is indexed, so files still being copied are not.
+    pub settle: Duration,
+    pub walk: WalkOptions,
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            debounce: Duration::from_secs(2),
+            settle: Duration::from_secs(2),
+            walk: WalkOptions::default(),
+        }
+    }
+}
+
+/// Keeps the store behind `callback` in sync with `roots` from filesystem
+/// events until `stop` is set. Each root is walked once the watch is set
+/// up, which picks up whatever changed while nothing was watching.
+pub fn watch_files(
+    roots: &[PathBuf],
+    callback: &mut dyn FsOpCallback,
+    options: &WatchOptions,
+    stop: &AtomicBool,
+) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut debouncer = new_debouncer(options.debounce, None, tx)?;
+    for root in roots {
+        debouncer.watch(root, RecursiveMode::Recursive)?;
+    }
+
+    for root in roots {
+        log_scan_errors(
+            root,
+            &walk_files_until(root, callback, &options.walk, stop)?,
+        );
+    }
+
+    let mut handler = EventHandler {
+        roots,
+        callback,
+        options,
+        stop,
+        pending: HashMap::new(),
+        dirty: false,
+    };
+
+    while !stop.load(Ordering::Relaxed) {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(events)) => {
+                for event in events.iter() {
+                    handler.on_event(event);
+                }
+            }
+            // the watch itself keeps going, later events are still handled
+            Ok(Err(errors)) => {
+                for e in errors {
+                    error!("watching the library failed: {e}");
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        handler.index_settled();
+        if handler.dirty {
+            // kept dirty, the next tick tries again
+            match handler.callback.flush() {
+                Ok(()) => handler.dirty = false,
+                Err(e) => error!("writing watched changes failed: {e:#}"),
+            }
+            for (path, e) in handler.callback.take_dropped() {
+                error!("dropped the change to {}: {e:#}", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Files a walk could not index, it doesn't fail for them.
+fn log_scan_errors(root: &Path, report: &ScanReport) {
+    for e in &report.errors {
+        warn!(
+            "indexing {} below {}: {}",
+            e.path,
+            root.display(),
+            e.message
+        );
+    }
+}
+
+struct EventHandler<'a> {
+    roots: &'a [PathBuf],
+    callback: &'a mut dyn FsOpCallback,
+    options: &'a WatchOptions,
+    stop: &'a AtomicBool,
+    /// Files waiting to settle, with their last seen identity and since when.
+    pending: HashMap<PathBuf, (FileIdentity, Instant)>,
+    dirty: bool,
+}
+
+impl EventHandler<'_> {
+    fn on_event(&mut self, event: &DebouncedEvent) {
+        if event.need_rescan() {
+            for root in self.roots {
+                match walk_files_until(root, self.callback, &self.options.walk, self.stop) {
+                    Ok(report) => log_scan_errors(root, &report),
+                    Err(e) => error!("rescanning {} failed: {e:#}", root.display()),
+                }
+            }
+            return;
+        }
+
+        match (event.kind, event.paths.as_slice()) {
+            (EventKind::Access(_), _) => {}
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                self.on_rename(from, to)
+            }
+            (_, paths) => {
+                for path in paths {
+                    self.on_path(path);
+                }
+            }
+        }
+    }
+
+    /// Brings whatever is at `path` up to date, whatever the event was.
+    fn on_path(&mut self, path: &Path) {
+        let Some(root) = self.roots.iter().find(|root| path.starts_with(root)) else {
+            return;
+        };
+        if media::is_ignored_path(path, root) {
+            return;
+        }
+
+        match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => self.on_dir(path),
+            Ok(metadata) if metadata.is_file() => {
+                if let Ok(identity) = FileIdentity::from_metadata(&metadata) {
+                    self.pending
+                        .insert(path.to_owned(), (identity, Instant::now()));
+                }
+            }
+            Ok(_) => {}
+            Err(_) => self.forget(path),
+        }
+    }
+
+    /// Files below `dir` which differ from their records wait to settle like
+    /// single files do, a directory may well still be copied in.
+    fn on_dir(&mut self, dir: &Path) {
+        self.flush();
+        let stored = match self.callback.stored_identities(dir) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("reading the records below {} failed: {e:#}", dir.display());
+                return;
+            }
+        };
+
+        let walker = jwalk::WalkDir::new(dir)
+            .skip_hidden(false)
+            .process_read_dir(|_, _, _, children| {
+                children.retain(|child| match child {
+                    Ok(child) => !media::is_ignored(child.file_name(), child.file_type().is_dir()),
+                    Err(_) => true,
+                })
+            });
+        for entry in walker {
+            if self.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let path = match entry {
+                Ok(entry) if entry.file_type().is_file() => entry.path(),
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!("walking {}: {e}", dir.display());
+                    continue;
+                }
+            };
+            if let Ok(identity) = std::fs::metadata(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|metadata| FileIdentity::from_metadata(&metadata))
+                && stored.get(&path) != Some(&identity)
+            {
+                self.on_path(&path);
+            }
+        }
+    }
+
+    /// Writes what is queued so the store can be read back, the watch goes
+    /// on if it can't.
+    fn flush(&mut self) {
+        if let Err(e) = self.callback.flush() {
+            error!("writing watched changes failed: {e:#}");
+        }
+    }
+
+    /// Moves the records under `from` to `to`, keeping what was extracted.
+    fn on_rename(&mut self, from: &Path, to: &Path) {
+        self.flush();
+        let stored = match self.callback.stored_identities(from) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("reading the records below {} failed: {e:#}", from.display());
+                return;
+            }
+        };
+
+        let mut moved = false;
+        for old in stored.keys() {
+            let Ok(relative) = old.strip_prefix(from) else {
+                continue;
+            };
+            let new = match relative.as_os_str().is_empty() {
+                true => to.to_owned(),
+                false => to.join(relative),
+            };
+            let Ok(metadata) = std::fs::metadata(&new) else {
+                continue;
+            };
+            let Ok(identity) = FileIdentity::from_metadata(&metadata) else {
+                continue;
+            };
+            match self.callback.on_move(old, &new, identity) {
+                Ok(()) => {
+                    moved = true;
+                    self.dirty = true;
+                }
+                Err(e) => error!("moving the record of {} failed: {e:#}", old.display()),
+            }
+        }
+
+        if !moved || to.is_dir() {
+            self.on_path(to);
+        }
+        self.on_path(from);
+    }
+
+    /// Everything stored at or below `path` is gone.
+    fn forget(&mut self, path: &Path) {
+        self.pending.retain(|pending, _| !pending.starts_with(path));
+
+        self.flush();
+        let stored = match self.callback.stored_identities(path) {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("reading the records below {} failed: {e:#}", path.display());
+                return;
+            }
+        };
+        for stored_path in stored.keys() {
+            match self.options.walk.missing.apply(self.callback, stored_path) {
+                Ok(()) => self.dirty = true,
+                Err(e) => error!("forgetting {} failed: {e:#}", stored_path.display()),
+            }
+        }
+    }
+
+    fn index_settled(&mut self) {
+        let now = Instant::now();
+        let settle = self.options.settle;
+
+        let mut settled = vec![];
+        self.pending.retain(|path, (identity, since)| {
+            let Ok(metadata) = std::fs::metadata(path) else {
+                // a removal event follows
+                return false;
+            };
+            match FileIdentity::from_metadata(&metadata) {
+                Ok(current) if current != *identity => {
+                    *identity = current;
+                    *since = now;
+                    true
+                }
+                Ok(_) if now.duration_since(*since) < settle => true,
+                Ok(_) => {
+                    settled.push((path.clone(), metadata));
+                    false
+                }
+                Err(_) => false,
+            }
+        });
+
+        for (path, metadata) in settled {
+            let media_type = match media::detect(&path) {
+                Ok(Some(media_type)) => media_type,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("indexing {}: {e:#}", path.display());
+                    continue;
+                }
+            };
+            let record = match PictureRecord::new(&path, &metadata, media_type) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("indexing {}: {e:#}", path.display());
+                    continue;
+                }
+            };
+            match self.callback.on_op(record) {
+                Ok(()) => self.dirty = true,
+                Err(e) => error!("writing the record of {} failed: {e:#}", path.display()),
+            }
+        }
+    }
+}